@@ -24,7 +24,7 @@ pub struct Object {
 
 /// An opaque id for a value in the GC's heap.
 /// Useful for implementing the [Trace] trait.
-#[derive(Hash, Eq, PartialEq, Copy, Clone)]
+#[derive(Hash, Eq, PartialEq, Copy, Clone, Debug)]
 pub struct Id {
     heap: usize,
     index: usize,
@@ -49,6 +49,12 @@ impl<T: Collect> PartialEq for Gc<T> {
 
 impl<T: Collect> Eq for Gc<T> {}
 
+impl<T: Collect> std::fmt::Debug for Gc<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Gc").field(&self.id).finish()
+    }
+}
+
 impl<T: Collect> std::hash::Hash for Gc<T> {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         self.id.hash(state);
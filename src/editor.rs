@@ -0,0 +1,606 @@
+//! A line editor for the REPL, with emacs-style key bindings and persistent history.
+
+use std::{
+    fs,
+    io::{self, BufRead, Write},
+    path::PathBuf,
+};
+
+use crate::grammar::Highlight;
+
+/// How many lines of history are kept.
+const HISTORY_SIZE: usize = 1000;
+
+/// What reading a line ended with.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Read {
+    Line(String),
+    /// Ctrl-C, the line is thrown away.
+    Interrupt,
+    /// Ctrl-D on an empty line, or the end of input.
+    Eof,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Char(char),
+    /// A control char, e.g. `Ctrl('a')` for Ctrl-A.
+    Ctrl(char),
+    /// A char typed with Alt held, or after Escape.
+    Alt(char),
+    Enter,
+    Tab,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    Unknown,
+}
+
+/// What the editor needs to know about the language being edited.
+pub trait Helper {
+    /// How to show each char of the line.
+    fn highlight(&self, line: &[char]) -> Vec<Highlight> {
+        vec![Highlight::Plain; line.len()]
+    }
+
+    /// Completions for the word before `cursor`: where the word starts and what could replace it.
+    fn complete(&self, _line: &[char], cursor: usize) -> (usize, Vec<String>) {
+        (cursor, Vec::new())
+    }
+}
+
+impl Helper for () {}
+
+/// The text being edited and the cursor in it, in chars.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Buffer {
+    pub chars: Vec<char>,
+    pub cursor: usize,
+}
+
+impl Buffer {
+    pub fn text(&self) -> String {
+        self.chars.iter().collect()
+    }
+
+    pub fn set(&mut self, text: &str) {
+        self.chars = text.chars().collect();
+        self.cursor = self.chars.len();
+    }
+
+    pub fn insert(&mut self, s: &str) {
+        for c in s.chars() {
+            self.chars.insert(self.cursor, c);
+            self.cursor += 1;
+        }
+    }
+
+    // Removes and returns the chars between the cursor and `to`, leaving the cursor at the start.
+    fn cut(&mut self, to: usize) -> String {
+        let (start, end) = (self.cursor.min(to), self.cursor.max(to));
+        self.cursor = start;
+        self.chars.drain(start..end).collect()
+    }
+
+    fn word_start(&self) -> usize {
+        let mut i = self.cursor;
+        while i > 0 && !self.chars[i - 1].is_alphanumeric() {
+            i -= 1;
+        }
+        while i > 0 && self.chars[i - 1].is_alphanumeric() {
+            i -= 1;
+        }
+        i
+    }
+
+    fn word_end(&self) -> usize {
+        let mut i = self.cursor;
+        while i < self.chars.len() && !self.chars[i].is_alphanumeric() {
+            i += 1;
+        }
+        while i < self.chars.len() && self.chars[i].is_alphanumeric() {
+            i += 1;
+        }
+        i
+    }
+}
+
+/// Lines entered before, oldest first, saved to a file if there is one.
+#[derive(Default)]
+pub struct History {
+    pub entries: Vec<String>,
+    path: Option<PathBuf>,
+}
+
+impl History {
+    /// Loads history from `path`, which is created when the first line is added.
+    pub fn load(path: PathBuf) -> Self {
+        let mut entries: Vec<String> = fs::read_to_string(&path)
+            .map(|s| s.lines().map(String::from).collect())
+            .unwrap_or_default();
+        if entries.len() > HISTORY_SIZE {
+            entries.drain(..entries.len() - HISTORY_SIZE);
+            // Failing to trim the file only makes it bigger.
+            let _ = fs::write(&path, entries.join("\n") + "\n");
+        }
+        History {
+            entries,
+            path: Some(path),
+        }
+    }
+
+    /// Adds a line, unless it's blank or repeats the previous one.
+    pub fn add(&mut self, line: &str) {
+        if line.trim().is_empty() || self.entries.last().is_some_and(|last| last == line) {
+            return;
+        }
+        self.entries.push(line.into());
+        if self.entries.len() > HISTORY_SIZE {
+            self.entries.remove(0);
+        }
+        if let Some(path) = &self.path {
+            // History is a convenience, so failing to save it isn't reported.
+            let _ = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{line}"));
+        }
+    }
+
+    /// The newest entry containing `query`, looking at entries before `before` only.
+    pub fn search(&self, query: &str, before: usize) -> Option<usize> {
+        self.entries[..before.min(self.entries.len())]
+            .iter()
+            .rposition(|entry| entry.contains(query))
+    }
+}
+
+// Ctrl-R in progress.
+struct Search {
+    query: String,
+    found: Option<usize>,
+    // The buffer before searching, restored by Ctrl-G.
+    original: Buffer,
+}
+
+/// The state of reading one line.
+#[derive(Default)]
+pub struct Session {
+    pub buffer: Buffer,
+    // The history entry shown, and the line that was being typed before moving through history.
+    history_index: Option<usize>,
+    draft: String,
+    search: Option<Search>,
+    /// Completions to list below the line, when Tab can't pick one.
+    pub candidates: Vec<String>,
+}
+
+#[derive(Default)]
+pub struct Editor {
+    pub history: History,
+    /// Whether to highlight the line with colors.
+    pub colors: bool,
+    // The text last cut, for Ctrl-Y.
+    killed: String,
+}
+
+impl Editor {
+    /// An editor with history kept in `~/.alush_history`, if there's a home directory.
+    /// Colors are used if stdout is a terminal, unless `NO_COLOR` is set.
+    pub fn new() -> Self {
+        let history = match std::env::var_os("HOME") {
+            Some(home) => History::load(PathBuf::from(home).join(".alush_history")),
+            None => History::default(),
+        };
+        Editor {
+            history,
+            colors: unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1
+                && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()),
+            killed: String::new(),
+        }
+    }
+
+    /// Reads a line, editing it in place if stdin is a terminal.
+    pub fn read_line(&mut self, prompt: &str, helper: &dyn Helper) -> io::Result<Read> {
+        let mut stdout = io::stdout();
+        let Ok(raw) = RawMode::enable() else {
+            write!(stdout, "{prompt}")?;
+            stdout.flush()?;
+            let mut line = String::new();
+            if io::stdin().lock().read_line(&mut line)? == 0 {
+                return Ok(Read::Eof);
+            }
+            if line.ends_with('\n') {
+                line.pop();
+            }
+            return Ok(Read::Line(line));
+        };
+        let mut session = Session::default();
+        let read = loop {
+            self.refresh(&mut stdout, prompt, &session, helper, true)?;
+            let Some(key) = read_key()? else {
+                break Read::Eof;
+            };
+            if let Some(read) = self.key(&mut session, key, helper) {
+                break read;
+            }
+            if key == Key::Ctrl('l') {
+                write!(stdout, "\x1b[H\x1b[2J")?;
+            }
+            if !session.candidates.is_empty() {
+                write!(stdout, "\r\n{}\r\n", session.candidates.join("  "))?;
+                session.candidates.clear();
+            }
+        };
+        // Show the line as entered, without the search prompt.
+        session.search = None;
+        session.buffer.cursor = session.buffer.chars.len();
+        self.refresh(&mut stdout, prompt, &session, helper, false)?;
+        if read == Read::Interrupt {
+            write!(stdout, "^C")?;
+        }
+        write!(stdout, "\r\n")?;
+        stdout.flush()?;
+        drop(raw);
+        if let Read::Line(line) = &read {
+            self.history.add(line);
+        }
+        Ok(read)
+    }
+
+    /// Applies a key to the session, returning how reading ended if it did.
+    pub fn key(&mut self, session: &mut Session, key: Key, helper: &dyn Helper) -> Option<Read> {
+        if let Some(search) = &mut session.search {
+            match key {
+                Key::Char(c) => {
+                    search.query.push(c);
+                    let before = search.found.map_or(self.history.entries.len(), |i| i + 1);
+                    search.found = self.history.search(&search.query, before);
+                }
+                Key::Backspace | Key::Ctrl('h') => {
+                    search.query.pop();
+                    search.found = self
+                        .history
+                        .search(&search.query, self.history.entries.len());
+                }
+                Key::Ctrl('r') => {
+                    let before = search.found.unwrap_or(self.history.entries.len());
+                    if let Some(found) = self.history.search(&search.query, before) {
+                        search.found = Some(found);
+                    }
+                }
+                Key::Ctrl('g') => {
+                    session.buffer = search.original.clone();
+                    session.search = None;
+                }
+                _ => {
+                    // Any other key takes the match and is then handled as usual.
+                    if let Some(found) = search.found {
+                        session.buffer.set(&self.history.entries[found]);
+                    }
+                    session.search = None;
+                    return self.key(session, key, helper);
+                }
+            }
+            return None;
+        }
+
+        let buffer = &mut session.buffer;
+        match key {
+            Key::Enter => return Some(Read::Line(buffer.text())),
+            Key::Ctrl('c') => return Some(Read::Interrupt),
+            Key::Ctrl('d') if buffer.chars.is_empty() => return Some(Read::Eof),
+            Key::Char(c) => buffer.insert(c.encode_utf8(&mut [0; 4])),
+            Key::Left | Key::Ctrl('b') => buffer.cursor = buffer.cursor.saturating_sub(1),
+            Key::Right | Key::Ctrl('f') => {
+                buffer.cursor = (buffer.cursor + 1).min(buffer.chars.len())
+            }
+            Key::Home | Key::Ctrl('a') => buffer.cursor = 0,
+            Key::End | Key::Ctrl('e') => buffer.cursor = buffer.chars.len(),
+            Key::Alt('b') => buffer.cursor = buffer.word_start(),
+            Key::Alt('f') => buffer.cursor = buffer.word_end(),
+            Key::Backspace | Key::Ctrl('h') if buffer.cursor > 0 => {
+                buffer.cut(buffer.cursor - 1);
+            }
+            Key::Delete | Key::Ctrl('d') if buffer.cursor < buffer.chars.len() => {
+                buffer.cut(buffer.cursor + 1);
+            }
+            Key::Ctrl('k') => self.killed = buffer.cut(buffer.chars.len()),
+            Key::Ctrl('u') => self.killed = buffer.cut(0),
+            Key::Ctrl('w') | Key::Alt('\x7f') => self.killed = buffer.cut(buffer.word_start()),
+            Key::Alt('d') => self.killed = buffer.cut(buffer.word_end()),
+            Key::Ctrl('y') => buffer.insert(&self.killed),
+            Key::Ctrl('t') => {
+                let len = buffer.chars.len();
+                if buffer.cursor > 0 && len > 1 {
+                    let i = buffer.cursor.min(len - 1);
+                    buffer.chars.swap(i - 1, i);
+                    buffer.cursor = i + 1;
+                }
+            }
+            Key::Up | Key::Ctrl('p') => {
+                let index = match session.history_index {
+                    None if self.history.entries.is_empty() => return None,
+                    None => {
+                        session.draft = buffer.text();
+                        self.history.entries.len() - 1
+                    }
+                    Some(index) => index.saturating_sub(1),
+                };
+                session.history_index = Some(index);
+                buffer.set(&self.history.entries[index]);
+            }
+            Key::Down | Key::Ctrl('n') => match session.history_index {
+                None => (),
+                Some(index) if index + 1 < self.history.entries.len() => {
+                    session.history_index = Some(index + 1);
+                    buffer.set(&self.history.entries[index + 1]);
+                }
+                Some(_) => {
+                    session.history_index = None;
+                    buffer.set(&session.draft);
+                }
+            },
+            Key::Ctrl('r') => {
+                session.search = Some(Search {
+                    query: String::new(),
+                    found: None,
+                    original: buffer.clone(),
+                })
+            }
+            Key::Tab => complete(session, helper),
+            _ => (),
+        }
+        None
+    }
+
+    // Redraws the line, scrolling it sideways if it doesn't fit the terminal.
+    // The paren at the cursor and its match are shown if `matching` is set.
+    fn refresh(
+        &self,
+        out: &mut impl Write,
+        prompt: &str,
+        session: &Session,
+        helper: &dyn Helper,
+        matching: bool,
+    ) -> io::Result<()> {
+        let (prompt, chars, cursor) = match &session.search {
+            Some(search) => {
+                let found = search
+                    .found
+                    .map_or("", |i| self.history.entries[i].as_str());
+                let prompt = format!("(reverse-i-search)'{}': ", search.query);
+                (prompt, found.chars().collect(), 0)
+            }
+            None => (
+                prompt.to_owned(),
+                session.buffer.chars.clone(),
+                session.buffer.cursor,
+            ),
+        };
+        let highlights = if self.colors && session.search.is_none() {
+            helper.highlight(&chars)
+        } else {
+            vec![Highlight::Plain; chars.len()]
+        };
+        let pair = if self.colors && matching {
+            matching_parens(&highlights, cursor)
+        } else {
+            None
+        };
+        let width = prompt.chars().count();
+        let room = terminal_columns().saturating_sub(width + 1).max(1);
+        let scroll = (cursor + 1).saturating_sub(room);
+        let mut visible = String::new();
+        let mut current = "";
+        for (i, &c) in chars.iter().enumerate().skip(scroll).take(room) {
+            let style = match highlights[i] {
+                _ if pair.is_some_and(|(open, close)| i == open || i == close) => "\x1b[1;7m",
+                Highlight::Plain | Highlight::Open | Highlight::Close => "",
+                Highlight::Head => "\x1b[1;34m",
+                Highlight::Quoted => "\x1b[32m",
+                Highlight::Dollar => "\x1b[35m",
+                Highlight::Comment => "\x1b[90m",
+                Highlight::Unmatched => "\x1b[1;31m",
+            };
+            if style != current {
+                visible.push_str("\x1b[0m");
+                visible.push_str(style);
+                current = style;
+            }
+            visible.push(c);
+        }
+        if !current.is_empty() {
+            visible.push_str("\x1b[0m");
+        }
+        write!(out, "\r{prompt}{visible}\x1b[K\r")?;
+        let column = width + cursor - scroll;
+        if column > 0 {
+            write!(out, "\x1b[{column}C")?;
+        }
+        out.flush()
+    }
+}
+
+/// The paren just before or at the cursor and the one it matches, if both are there.
+pub fn matching_parens(highlights: &[Highlight], cursor: usize) -> Option<(usize, usize)> {
+    let mut open = Vec::new();
+    let mut pairs = Vec::new();
+    for (i, highlight) in highlights.iter().enumerate() {
+        match highlight {
+            Highlight::Open => open.push(i),
+            Highlight::Close => pairs.extend(open.pop().map(|o| (o, i))),
+            _ => (),
+        }
+    }
+    [cursor.checked_sub(1), Some(cursor)]
+        .into_iter()
+        .flatten()
+        .find_map(|at| pairs.iter().find(|&&(o, c)| o == at || c == at).copied())
+}
+
+// Replaces the word before the cursor with its only completion,
+// or with the longest prefix of all of them, listing them if that doesn't help.
+fn complete(session: &mut Session, helper: &dyn Helper) {
+    let buffer = &mut session.buffer;
+    let (start, candidates) = helper.complete(&buffer.chars, buffer.cursor);
+    let word: String = buffer.chars[start..buffer.cursor].iter().collect();
+    let replacement = match &candidates[..] {
+        [] => return,
+        // Directories are likely to be completed further.
+        [only] if only.trim_end_matches('\'').ends_with('/') => only.clone(),
+        [only] => format!("{only} "),
+        [first, rest @ ..] => {
+            let mut prefix = first.as_str();
+            for candidate in rest {
+                let common = prefix
+                    .char_indices()
+                    .zip(candidate.chars())
+                    .find(|((_, a), b)| a != b)
+                    .map_or(prefix.len().min(candidate.len()), |((i, _), _)| i);
+                prefix = &prefix[..common];
+            }
+            if prefix.chars().count() <= word.chars().count() {
+                session.candidates = candidates;
+                return;
+            }
+            prefix.to_owned()
+        }
+    };
+    buffer.cut(start);
+    buffer.insert(&replacement);
+}
+
+// Puts the terminal in raw mode until dropped.
+struct RawMode(libc::termios);
+
+impl RawMode {
+    fn enable() -> io::Result<Self> {
+        unsafe {
+            if libc::isatty(libc::STDIN_FILENO) == 0 {
+                return Err(io::Error::other("stdin is not a terminal"));
+            }
+            let mut termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let original = termios;
+            termios.c_iflag &=
+                !(libc::BRKINT | libc::ICRNL | libc::INPCK | libc::ISTRIP | libc::IXON);
+            termios.c_cflag |= libc::CS8;
+            termios.c_lflag &= !(libc::ECHO | libc::ICANON | libc::IEXTEN | libc::ISIG);
+            termios.c_cc[libc::VMIN] = 1;
+            termios.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, &termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(RawMode(original))
+        }
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, &self.0) };
+    }
+}
+
+fn terminal_columns() -> usize {
+    unsafe {
+        let mut size: libc::winsize = std::mem::zeroed();
+        if libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) == 0 && size.ws_col > 0 {
+            size.ws_col.into()
+        } else {
+            80
+        }
+    }
+}
+
+fn read_byte() -> io::Result<Option<u8>> {
+    let mut byte = 0u8;
+    loop {
+        let n = unsafe { libc::read(libc::STDIN_FILENO, (&raw mut byte).cast(), 1) };
+        match n {
+            1 => return Ok(Some(byte)),
+            0 => return Ok(None),
+            _ => {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+// Reads a key from the terminal, or `None` at the end of input.
+fn read_key() -> io::Result<Option<Key>> {
+    let Some(byte) = read_byte()? else {
+        return Ok(None);
+    };
+    let key = match byte {
+        b'\r' | b'\n' => Key::Enter,
+        b'\t' => Key::Tab,
+        0x7f => Key::Backspace,
+        0x1b => match read_byte()? {
+            Some(b'[') => escape_sequence()?,
+            Some(b'O') => match read_byte()? {
+                Some(b'H') => Key::Home,
+                Some(b'F') => Key::End,
+                _ => Key::Unknown,
+            },
+            Some(byte) if byte.is_ascii() => Key::Alt(byte.into()),
+            _ => Key::Unknown,
+        },
+        1..=26 => Key::Ctrl((b'a' + byte - 1).into()),
+        0..=31 => Key::Unknown,
+        _ => {
+            // Multibyte UTF-8 chars have as many leading ones as bytes.
+            let len = byte.leading_ones().max(1) as usize;
+            let mut bytes = vec![byte];
+            for _ in 1..len {
+                match read_byte()? {
+                    Some(byte) => bytes.push(byte),
+                    None => break,
+                }
+            }
+            match std::str::from_utf8(&bytes)
+                .ok()
+                .and_then(|s| s.chars().next())
+            {
+                Some(c) => Key::Char(c),
+                None => Key::Unknown,
+            }
+        }
+    };
+    Ok(Some(key))
+}
+
+// Reads the rest of `ESC [ <params> <final>`.
+fn escape_sequence() -> io::Result<Key> {
+    let mut params = String::new();
+    loop {
+        let Some(byte) = read_byte()? else {
+            return Ok(Key::Unknown);
+        };
+        if (0x40..=0x7e).contains(&byte) {
+            return Ok(match (byte, params.as_str()) {
+                (b'A', _) => Key::Up,
+                (b'B', _) => Key::Down,
+                (b'C', _) => Key::Right,
+                (b'D', _) => Key::Left,
+                (b'H', _) | (b'~', "1" | "7") => Key::Home,
+                (b'F', _) | (b'~', "4" | "8") => Key::End,
+                (b'~', "3") => Key::Delete,
+                _ => Key::Unknown,
+            });
+        }
+        params.push(byte.into());
+    }
+}
@@ -1,7 +1,60 @@
 pub type Commands = Vec<Command>;
-pub type Command = Vec<Expr>;
 
-use crate::syntax::Input;
+#[derive(Debug)]
+pub struct Command {
+    pub exprs: Vec<Expr>,
+    pub span: Span,
+}
+
+use crate::syntax::{Input, Position, Span};
+
+/// Why the source couldn't be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// What the parser was looking for, e.g. "')' to close the closure opened at 1:5".
+    pub expected: String,
+    /// The char found instead, `None` at the end of input.
+    pub found: Option<char>,
+    /// Where the unexpected char is.
+    pub position: Position,
+}
+
+impl ParseError {
+    /// Whether the source ended too early, so that more input could complete it.
+    pub fn is_incomplete(&self) -> bool {
+        self.found.is_none()
+    }
+
+    /// An empty span at the position of the error, for excerpts.
+    pub fn span(&self) -> Span {
+        Span {
+            start: self.position,
+            end: self.position,
+        }
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "expected {}, found ", self.expected)?;
+        match self.found {
+            None => write!(f, "end of input"),
+            Some('\n') => write!(f, "newline"),
+            Some('\t') => write!(f, "tab"),
+            Some(c) => write!(f, "'{c}'"),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, ParseError>;
+
+fn error<T>(i: &mut Input, expected: impl Into<String>) -> Result<T> {
+    Err(ParseError {
+        expected: expected.into(),
+        found: peek(i),
+        position: i.here(),
+    })
+}
 
 fn peek(i: &mut Input) -> Option<char> {
     i.peek().copied()
@@ -15,8 +68,12 @@ fn accept(i: &mut Input, b: char) -> bool {
     false
 }
 
-fn expect(i: &mut Input, b: char) -> Option<()> {
-    if accept(i, b) { Some(()) } else { None }
+fn expect(i: &mut Input, b: char, expected: impl Into<String>) -> Result<()> {
+    if accept(i, b) {
+        Ok(())
+    } else {
+        error(i, expected)
+    }
 }
 
 fn not(i: &mut Input, bs: &str) -> bool {
@@ -34,13 +91,31 @@ fn not(i: &mut Input, bs: &str) -> bool {
 
 #[derive(Debug)]
 pub enum Expr {
-    String { dollar: bool, value: String },
-    Commands { dollar: bool, value: Commands },
+    String {
+        dollar: bool,
+        quoted: bool,
+        value: String,
+        span: Span,
+    },
+    Commands {
+        dollar: bool,
+        value: Commands,
+        span: Span,
+    },
 }
 
-fn string(i: &mut Input) -> Option<String> {
+impl Expr {
+    pub fn span(&self) -> Span {
+        match self {
+            Expr::String { span, .. } | Expr::Commands { span, .. } => *span,
+        }
+    }
+}
+
+fn string(i: &mut Input) -> Result<String> {
+    let start = i.here();
     if accept(i, '\'') {
-        Some(quoted_string(i)?)
+        quoted_string(i, start)
     } else {
         let mut s = String::new();
 
@@ -49,9 +124,9 @@ fn string(i: &mut Input) -> Option<String> {
                 None | Some(' ' | '\n' | ')' | '\t') => {
                     if s.is_empty() {
                         // Bad start of string.
-                        return None;
+                        return error(i, "an expression");
                     } else {
-                        return Some(s);
+                        return Ok(s);
                     }
                 }
                 Some(b) => {
@@ -63,7 +138,7 @@ fn string(i: &mut Input) -> Option<String> {
     }
 }
 
-fn quoted_string(i: &mut Input) -> Option<String> {
+fn quoted_string(i: &mut Input, start: Position) -> Result<String> {
     let mut s = String::new();
 
     loop {
@@ -71,148 +146,394 @@ fn quoted_string(i: &mut Input) -> Option<String> {
             if accept(i, '\'') {
                 s.push('\'');
             } else {
-                return Some(s);
+                return Ok(s);
             }
         } else if let Some(b) = i.next() {
             s.push(b);
         } else {
-            // Unclosed quoted string.
-            return None;
+            return error(
+                i,
+                format!("a closing quote for the string starting at {start}"),
+            );
         }
     }
 }
 
-fn expr(i: &mut Input) -> Option<Expr> {
+// Errors that list parsers recovered from are pushed to `errors`.
+fn expr(i: &mut Input, errors: &mut Vec<ParseError>) -> Result<Expr> {
+    let start = i.here();
     let dollar = accept(i, '$');
 
     if accept(i, '(') {
-        Some(Expr::Commands {
+        let opened = if dollar { "block" } else { "closure" };
+        let closing = format!("')' to close the {opened} opened at {start}");
+        let value = commands(i, &closing, errors)?;
+        Ok(Expr::Commands {
             dollar,
-            value: commands(i)?,
+            value,
+            span: Span {
+                start,
+                end: i.here(),
+            },
         })
     } else {
-        Some(Expr::String {
+        let quoted = peek(i) == Some('\'');
+        let value = string(i)?;
+        Ok(Expr::String {
             dollar,
-            value: string(i)?,
+            quoted,
+            value,
+            span: Span {
+                start,
+                end: i.here(),
+            },
         })
     }
 }
 
-fn commands(i: &mut Input) -> Option<Commands> {
+/// The operator if `expr` is an unquoted `<`, `>`, `>>`, `2>`, `2>>` or `2>&1`,
+/// which redirects a program's input or output when it isn't the head of a command
+/// that runs a program, and is a plain string anywhere else.
+/// All but `2>&1` are followed by a file.
+pub fn redirection(expr: &Expr) -> Option<&str> {
+    match expr {
+        Expr::String {
+            dollar: false,
+            quoted: false,
+            value,
+            ..
+        } if matches!(value.as_str(), "<" | ">" | ">>" | "2>" | "2>>" | "2>&1") => Some(value),
+        _ => None,
+    }
+}
+
+// `closing` describes the ')' that ends the commands, for errors.
+fn commands(i: &mut Input, closing: &str, errors: &mut Vec<ParseError>) -> Result<Commands> {
     if accept(i, '\n') {
-        multiline_commands(i)
+        multiline_commands_until(i, closing, errors)
     } else {
-        inline_command(i)
+        inline_command(i, closing, errors)
     }
 }
 
-pub fn file(i: &mut Input) -> Option<Commands> {
+// Fails with the first error, whether it was recovered from or not.
+fn strict<T>(result: Result<T>, errors: Vec<ParseError>) -> Result<T> {
+    match errors.into_iter().next() {
+        Some(e) => Err(e),
+        None => result,
+    }
+}
+
+pub fn file(i: &mut Input) -> Result<Commands> {
+    let (commands, errors) = file_recovering(i);
+    strict(Ok(commands), errors)
+}
+
+/// Parses a whole file, skipping over broken commands instead of stopping at the first one.
+/// Returns the commands that parsed and every error, in source order.
+pub fn file_recovering(i: &mut Input) -> (Commands, Vec<ParseError>) {
     let mut commands = Commands::new();
+    let mut errors = Vec::new();
     loop {
         while let Some(' ' | '\t') = peek(i) {
             i.next();
         }
         if peek(i).is_none() {
-            return Some(commands);
+            return (commands, errors);
         } else if accept(i, '#') {
-            comment(i)?
+            if let Err(e) = comment(i) {
+                errors.push(e);
+            }
         } else if accept(i, '\n') {
         } else {
-            commands.push(command(i)?);
-            expect(i, '\n')?;
+            let result = command_in(i, &mut errors)
+                .and_then(|c| expect(i, '\n', "a newline after the command").map(|()| c));
+            match result {
+                Ok(c) => commands.push(c),
+                Err(e) => {
+                    errors.push(e);
+                    skip_command(i, false);
+                }
+            }
         }
     }
 }
 
-pub fn multiline_commands(i: &mut Input) -> Option<Commands> {
+pub fn multiline_commands(i: &mut Input) -> Result<Commands> {
+    let mut errors = Vec::new();
+    let result = multiline_commands_until(i, "')'", &mut errors);
+    strict(result, errors)
+}
+
+fn multiline_commands_until(
+    i: &mut Input,
+    closing: &str,
+    errors: &mut Vec<ParseError>,
+) -> Result<Commands> {
     let mut commands = Commands::new();
     loop {
         while let Some(' ' | '\t') = peek(i) {
             i.next();
         }
         if accept(i, ')') {
-            return Some(commands);
+            return Ok(commands);
+        } else if peek(i).is_none() {
+            return error(i, closing);
         } else if accept(i, '#') {
             comment(i)?
         } else if accept(i, '\n') {
         } else {
-            commands.push(command(i)?);
-            expect(i, '\n')?;
+            let result = command_in(i, errors)
+                .and_then(|c| expect(i, '\n', "a newline after the command").map(|()| c));
+            match result {
+                Ok(c) => commands.push(c),
+                // Unclosed commands can't be recovered from.
+                Err(e) if e.is_incomplete() => return Err(e),
+                Err(e) => {
+                    errors.push(e);
+                    skip_command(i, true);
+                }
+            }
+        }
+    }
+}
+
+// Skips the rest of a broken command, up to and including the next newline.
+// Nested commands stop before a ')' that closes them, so that they can still be closed.
+fn skip_command(i: &mut Input, nested: bool) {
+    let mut depth = 0usize;
+    while let Some(c) = peek(i) {
+        match c {
+            '\n' if depth == 0 => {
+                i.next();
+                return;
+            }
+            ')' if depth == 0 && nested => return,
+            ')' => depth = depth.saturating_sub(1),
+            '(' => depth += 1,
+            '\'' => {
+                i.next();
+                while not(i, "'") {}
+            }
+            _ => (),
         }
+        i.next();
     }
 }
 
-fn comment(i: &mut Input) -> Option<()> {
+fn comment(i: &mut Input) -> Result<()> {
     while not(i, "\n") {}
-    expect(i, '\n')
+    expect(i, '\n', "a newline to end the comment")
 }
 
 // Initially adapted from multiline_commands().
-pub fn shell(i: &mut Input) -> Option<Command> {
+/// Parses the next command typed interactively, or `None` if only blanks and comments are left.
+/// An incomplete command fails at the end of input even if it has other errors,
+/// so that the REPL knows to read more.
+pub fn shell(i: &mut Input) -> Result<Option<Command>> {
     loop {
         while let Some(' ' | '\t') = peek(i) {
             i.next();
         }
-        if accept(i, '#') {
+        if peek(i).is_none() {
+            return Ok(None);
+        } else if accept(i, '#') {
             comment(i)?
         } else if accept(i, '\n') {
         } else {
-            let c = command(i)?;
-            expect(i, '\n')?;
-            return Some(c);
+            let mut errors = Vec::new();
+            let result = command_in(i, &mut errors)
+                .and_then(|c| expect(i, '\n', "a newline after the command").map(|()| c));
+            return match result {
+                Err(e) if e.is_incomplete() => Err(e),
+                result => strict(result.map(Some), errors),
+            };
         }
     }
 }
 
-fn inline_command(i: &mut Input) -> Option<Commands> {
+fn inline_command(i: &mut Input, closing: &str, errors: &mut Vec<ParseError>) -> Result<Commands> {
     if accept(i, ')') {
-        Some(Commands::new())
+        Ok(Commands::new())
     } else {
-        let commands = vec![command(i)?];
+        let commands = vec![command_in(i, errors)?];
 
-        expect(i, ')')?;
+        expect(i, ')', closing)?;
 
-        Some(commands)
+        Ok(commands)
     }
 }
 
-fn multiline_command_part(i: &mut Input) -> Option<Vec<Expr>> {
+// `start` is the position of the backslash that opened the part.
+fn multiline_command_part(
+    i: &mut Input,
+    start: Position,
+    errors: &mut Vec<ParseError>,
+) -> Result<Vec<Expr>> {
     let mut exprs = Vec::new();
     loop {
         while let Some(' ' | '\t') = peek(i) {
             i.next();
         }
         if accept(i, ';') {
-            return Some(exprs);
+            return Ok(exprs);
+        } else if peek(i).is_none() {
+            return error(
+                i,
+                format!("';' to end the multiline command part started at {start}"),
+            );
         } else if accept(i, '#') {
-            comment(i);
+            comment(i)?;
         } else if accept(i, '\n') {
         }
         // We could've called back to command here to allow for recursion.
         // But it's not clear that it's better.
         else {
-            exprs.push(expr(i)?);
+            exprs.push(expr(i, errors)?);
 
             while accept(i, ' ') {
-                exprs.push(expr(i)?);
+                exprs.push(expr(i, errors)?);
             }
         }
     }
 }
 
-pub fn command(i: &mut Input) -> Option<Command> {
-    let mut command = Command::new();
+pub fn command(i: &mut Input) -> Result<Command> {
+    let mut errors = Vec::new();
+    let result = command_in(i, &mut errors);
+    strict(result, errors)
+}
 
-    command.push(expr(i)?);
+fn command_in(i: &mut Input, errors: &mut Vec<ParseError>) -> Result<Command> {
+    let start = i.here();
+    let mut exprs = Vec::new();
+
+    exprs.push(expr(i, errors)?);
 
     while accept(i, ' ') {
+        let part = i.here();
         if accept(i, '\\') {
-            let mut exprs = multiline_command_part(i)?;
-            command.append(&mut exprs);
+            exprs.append(&mut multiline_command_part(i, part, errors)?);
         } else {
-            command.push(expr(i)?);
+            exprs.push(expr(i, errors)?);
         }
     }
 
-    Some(command)
+    Ok(Command {
+        exprs,
+        span: Span {
+            start,
+            end: i.here(),
+        },
+    })
+}
+
+/// How a char of source is shown when highlighting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Highlight {
+    Plain,
+    /// The first string of a command.
+    Head,
+    /// A quoted string.
+    Quoted,
+    /// `$name`, or the `$` of `$(...)`.
+    Dollar,
+    Comment,
+    Open,
+    Close,
+    /// A ')' that doesn't close anything.
+    Unmatched,
+}
+
+/// Classifies chars of possibly incomplete or broken source by the rules of the grammar above.
+pub fn highlight(source: &[char]) -> Vec<Highlight> {
+    let mut highlights = vec![Highlight::Plain; source.len()];
+    // Whether a multiline command part is open, for each level of parens.
+    let mut parts = vec![false];
+    // Whether the next string is the head of a command.
+    let mut head = true;
+    let mut line_start = true;
+    let mut i = 0;
+    while i < source.len() {
+        let in_part = *parts.last().unwrap();
+        let start = i;
+        let mut highlight = Highlight::Plain;
+        match source[i] {
+            ' ' | '\t' => i += 1,
+            '\n' => {
+                i += 1;
+                head = !in_part;
+                line_start = true;
+                continue;
+            }
+            '#' if head || in_part && line_start => {
+                while i < source.len() && source[i] != '\n' {
+                    i += 1;
+                }
+                highlight = Highlight::Comment;
+            }
+            '(' => {
+                i += 1;
+                parts.push(false);
+                head = true;
+                highlight = Highlight::Open;
+            }
+            ')' => {
+                i += 1;
+                head = false;
+                highlight = if parts.len() > 1 {
+                    parts.pop();
+                    Highlight::Close
+                } else {
+                    Highlight::Unmatched
+                };
+            }
+            '\\' if !head => {
+                i += 1;
+                *parts.last_mut().unwrap() = true;
+            }
+            ';' if in_part && !head => {
+                i += 1;
+                *parts.last_mut().unwrap() = false;
+            }
+            '\'' => {
+                i += 1;
+                loop {
+                    match source.get(i) {
+                        None => break,
+                        Some('\'') if source.get(i + 1) == Some(&'\'') => i += 2,
+                        Some('\'') => {
+                            i += 1;
+                            break;
+                        }
+                        Some(_) => i += 1,
+                    }
+                }
+                head = false;
+                highlight = Highlight::Quoted;
+            }
+            '$' if source.get(i + 1) == Some(&'(') => {
+                i += 1;
+                highlight = Highlight::Dollar;
+            }
+            c => {
+                while i < source.len() && !matches!(source[i], ' ' | '\t' | '\n' | ')') {
+                    i += 1;
+                }
+                highlight = if c == '$' {
+                    Highlight::Dollar
+                } else if head {
+                    Highlight::Head
+                } else {
+                    Highlight::Plain
+                };
+                head = false;
+            }
+        }
+        if !matches!(source[start], ' ' | '\t') {
+            line_start = false;
+        }
+        highlights[start..i].fill(highlight);
+    }
+    highlights
 }
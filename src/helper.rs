@@ -0,0 +1,171 @@
+//! What the REPL's editor knows about alush: highlighting and Tab completion of
+//! variables, map and list methods, map keys, executables and paths.
+
+use std::{collections::BTreeSet, fs, os::unix::fs::PermissionsExt};
+
+use crate::{
+    editor::Helper,
+    grammar::{self, Highlight},
+    interpreter::{Env, Key, LIST_METHODS, MAP_METHODS, Value},
+};
+
+impl Helper for Env {
+    fn highlight(&self, line: &[char]) -> Vec<Highlight> {
+        grammar::highlight(line)
+    }
+
+    fn complete(&self, line: &[char], cursor: usize) -> (usize, Vec<String>) {
+        let mut start = cursor;
+        while start > 0 && !matches!(line[start - 1], ' ' | '\t' | '(' | ')') {
+            start -= 1;
+        }
+        let word: String = line[start..cursor].iter().collect();
+        let words = command_words(&line[..start]);
+
+        let candidates = if let Some(name) = word.strip_prefix('$') {
+            let names = self
+                .variable_names()
+                .into_iter()
+                .filter(|v| v.starts_with(name));
+            names.map(|v| format!("${v}")).collect()
+        } else {
+            match &words[..] {
+                // A head that isn't a variable runs a program. Listing every program
+                // for an empty word would bury the variables.
+                [] => {
+                    let mut names = self.variable_names();
+                    names.retain(|v| v.starts_with(&word));
+                    if !word.is_empty() {
+                        names.extend(executables(&word));
+                    }
+                    names.into_iter().collect()
+                }
+                [unix] if unix == "unix" => executables(&word),
+                [head, rest @ ..] => match self.method_completions(head, rest) {
+                    Some(keys) => keys.into_iter().filter(|k| k.starts_with(&word)).collect(),
+                    None => paths(&word),
+                },
+            }
+        };
+        (start, candidates.into_iter().map(|c| quote(&c)).collect())
+    }
+}
+
+impl Env {
+    // Every variable in scope, including builtins, sorted.
+    fn variable_names(&self) -> BTreeSet<String> {
+        let mut names = BTreeSet::new();
+        let mut maybe_stack = Some(self.stack);
+        while let Some(stack) = maybe_stack {
+            let stack = self.gc.get(stack);
+            names.extend(stack.frame.variables.keys().cloned());
+            maybe_stack = stack.up;
+        }
+        names
+    }
+
+    // Methods of a map or list named `head`, or a map's keys if the method takes one.
+    fn method_completions(&self, head: &str, args: &[String]) -> Option<Vec<String>> {
+        let mut maybe_stack = Some(self.stack);
+        let value = loop {
+            let stack = self.gc.get(maybe_stack?);
+            if let Some(&value) = stack.frame.variables.get(head) {
+                break value;
+            }
+            maybe_stack = stack.up;
+        };
+        let (methods, map) = match self.gc.get(value) {
+            Value::Map(map) => (MAP_METHODS, Some(map)),
+            Value::List(_) => (LIST_METHODS, None),
+            _ => return None,
+        };
+        match args {
+            [] => Some(methods.iter().map(|(name, ..)| name.to_string()).collect()),
+            [method] if matches!(method.as_str(), "get" | "set" | "del" | "has") => {
+                // Sorted, like every other completion.
+                let mut keys: Vec<_> = map?
+                    .keys()
+                    .filter_map(|k| match k {
+                        Key::String(k) => Some(k.clone()),
+                        Key::Bytes(_) | Key::Value(_) => None,
+                    })
+                    .collect();
+                keys.sort();
+                Some(keys)
+            }
+            _ => None,
+        }
+    }
+}
+
+// The words of the command being typed before the current word, e.g. `m get` in `(m get k`.
+// Blocks and closures before the current word are skipped.
+fn command_words(before: &[char]) -> Vec<String> {
+    let mut words = vec![String::new()];
+    let mut depth = 0usize;
+    for &c in before.iter().rev() {
+        match c {
+            ')' => depth += 1,
+            '(' if depth == 0 => break,
+            '(' => depth -= 1,
+            ' ' | '\t' if depth == 0 => words.push(String::new()),
+            _ if depth == 0 => words.last_mut().unwrap().insert(0, c),
+            _ => (),
+        }
+    }
+    words.retain(|w| !w.is_empty() && w != "$" && w != "\\");
+    words.reverse();
+    words
+}
+
+// Programs in $PATH starting with `prefix`.
+fn executables(prefix: &str) -> Vec<String> {
+    let mut names = BTreeSet::new();
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    for dir in std::env::split_paths(&path) {
+        let Ok(entries) = fs::read_dir(dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            // Following symlinks, which many programs are.
+            let executable = fs::metadata(entry.path())
+                .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0);
+            if executable && name.starts_with(prefix) {
+                names.insert(name);
+            }
+        }
+    }
+    names.into_iter().collect()
+}
+
+// Files starting with `prefix`, with a slash after directories.
+fn paths(prefix: &str) -> Vec<String> {
+    let (dir, name) = match prefix.rfind('/') {
+        Some(i) => (&prefix[..=i], &prefix[i + 1..]),
+        None => ("", prefix),
+    };
+    let Ok(entries) = fs::read_dir(if dir.is_empty() { "." } else { dir }) else {
+        return Vec::new();
+    };
+    let mut paths = BTreeSet::new();
+    for entry in entries.flatten() {
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        // Hidden files are only completed when asked for.
+        if !file_name.starts_with(name) || (file_name.starts_with('.') && !name.starts_with('.')) {
+            continue;
+        }
+        let slash = if entry.path().is_dir() { "/" } else { "" };
+        paths.insert(format!("{dir}{file_name}{slash}"));
+    }
+    paths.into_iter().collect()
+}
+
+// Quotes a completion that wouldn't parse as a single string.
+fn quote(s: &str) -> String {
+    if s.is_empty() || s.contains([' ', '\t', '\n', ')', '\'']) {
+        format!("'{}'", s.replace('\'', "''"))
+    } else {
+        s.to_owned()
+    }
+}
@@ -1,54 +1,242 @@
-use std::{
-    collections::{BTreeMap, HashMap},
-    rc::Rc,
-};
+use std::{borrow::Cow, cmp::Ordering, collections::BTreeMap, rc::Rc};
 
 use gc::{self, Gc};
 
-use crate::syntax::{self, Expr};
+use crate::{
+    ordered_map::OrderedMap,
+    syntax::{self, Expr, Span},
+};
 
+mod bigint;
 mod builtins;
+mod macros;
+mod number;
+mod process;
+
+pub use bigint::BigInt;
+pub use macros::Macros;
+pub use number::Number;
+pub use process::{Program, Redirect, Stage};
 
 pub enum Callable {
     Closure {
         code: Rc<syntax::Commands>,
         stack: Gc<Stack>,
+        /// Shown by `help`, attached with `doc`.
+        doc: Option<String>,
+        /// Whether arguments are passed as unevaluated [Code], made by `lazy`.
+        lazy: bool,
     },
 }
 
-type Builtin = fn(&mut Env, args: &[Gc<Value>]) -> Result;
-type LazyBuiltin = fn(&mut Env, args: &[syntax::Expr]) -> Result;
+/// An unevaluated expression with the scope to evaluate it in, evaluated by `eval`.
+/// Lazy closures get their arguments as code.
+pub struct Code {
+    pub expr: syntax::Expr,
+    pub stack: Gc<Stack>,
+}
+
+/// A function implemented in Rust, described for printing and errors.
+/// `F` is a closure, so that it can hold Rust state.
+pub struct Builtin<F: ?Sized> {
+    pub name: String,
+    /// How it's called, e.g. `set <name> <value>`.
+    pub usage: String,
+    /// What it does, in a sentence or two.
+    pub help: String,
+    pub f: F,
+}
+
+impl<F: Fn(&mut Env, &[Gc<Value>]) -> Result> Builtin<F> {
+    /// A builtin for [Env::register]. Bounding `f` here lets a closure leave out
+    /// the types of its arguments, as in `|env, args| ...`.
+    pub fn new(name: &str, usage: &str, help: &str, f: F) -> Self {
+        Builtin {
+            name: name.into(),
+            usage: usage.into(),
+            help: help.into(),
+            f,
+        }
+    }
+}
+
+impl<F: Fn(&mut Env, &[syntax::Expr]) -> Result> Builtin<F> {
+    /// Like [Builtin::new], but for [Env::register_lazy].
+    pub fn lazy(name: &str, usage: &str, help: &str, f: F) -> Self {
+        Builtin {
+            name: name.into(),
+            usage: usage.into(),
+            help: help.into(),
+            f,
+        }
+    }
+}
+
+/// A builtin that gets its arguments evaluated and rooted, and roots its result.
+pub type Eager = dyn Fn(&mut Env, &[Gc<Value>]) -> Result;
+/// A builtin that gets its arguments unevaluated, to evaluate them as it sees fit.
+pub type Lazy = dyn Fn(&mut Env, &[syntax::Expr]) -> Result;
+
+// Builtins that every Env starts with don't need state.
+type EagerFn = fn(&mut Env, &[Gc<Value>]) -> Result;
+type LazyFn = fn(&mut Env, &[syntax::Expr]) -> Result;
 
 pub enum Value {
     String(String),
-    Builtin(Builtin),
+    /// Bytes that may not be UTF-8, like what a program printed.
+    Bytes(Vec<u8>),
+    /// Where a program's input or output goes, passed to it among its arguments.
+    Redirect(Redirect),
+    Number(Number),
+    Builtin(Rc<Builtin<Eager>>),
     Callable(Callable),
-    LazyBuiltin(LazyBuiltin),
-    Exception(Gc<Value>),
-    Map(BTreeMap<String, Gc<Value>>),
+    LazyBuiltin(Rc<Builtin<Lazy>>),
+    Code(Code),
+    /// Keys are kept in the order they were added in.
+    Map(OrderedMap<Key, Gc<Value>>),
+    List(Vec<Gc<Value>>),
 }
 
 impl gc::Collect for Value {
     fn trace(&self) -> Vec<gc::Id> {
         match self {
             Value::String(_) => Vec::new(),
+            Value::Bytes(_) => Vec::new(),
+            Value::Redirect(_) => Vec::new(),
+            Value::Number(_) => Vec::new(),
             Value::Builtin(_) => Vec::new(),
-            Value::Callable(Callable::Closure { code: _, stack }) => vec![stack.id],
-            Value::Exception(v) => vec![v.id],
+            Value::Callable(Callable::Closure { stack, .. }) => vec![stack.id],
             Value::LazyBuiltin(_) => Vec::new(),
+            Value::Code(Code { stack, .. }) => vec![stack.id],
             Value::Map(m) => {
                 let mut result = Vec::new();
-                for &v in m.values() {
+                for (k, &v) in m {
+                    if let Key::Value(k) = k {
+                        result.push(k.id);
+                    }
                     result.push(v.id);
                 }
                 result
             }
+            Value::List(l) => l.iter().map(|v| v.id).collect(),
+        }
+    }
+}
+
+/// A key of a map. Strings and bytes are the same key if they're equal,
+/// so that they can be built anew to look a value up, and other values if they're the same value.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Key {
+    String(String),
+    Bytes(Vec<u8>),
+    Value(Gc<Value>),
+}
+
+impl Key {
+    // Strings are sorted before bytes, and bytes before other values, which keep their order.
+    fn sort(&self, other: &Key) -> Ordering {
+        match (self, other) {
+            (Key::String(a), Key::String(b)) => a.cmp(b),
+            (Key::Bytes(a), Key::Bytes(b)) => a.cmp(b),
+            (Key::Value(_), Key::Value(_)) => Ordering::Equal,
+            (Key::String(_), _) | (Key::Bytes(_), Key::Value(_)) => Ordering::Less,
+            (Key::Value(_), _) | (Key::Bytes(_), Key::String(_)) => Ordering::Greater,
         }
     }
 }
 
+/// Methods that maps are called with, as in `m get k`: names, usages and help.
+pub const MAP_METHODS: &[(&str, &str, &str)] = &[
+    (
+        "get",
+        "map get <key>",
+        "Returns the value of a key, failing if there's none.",
+    ),
+    ("set", "map set <key> <value>", "Sets the value of a key."),
+    ("del", "map del <key>", "Removes a key, if it's there."),
+    ("has", "map has <key>", "Whether there's a value for a key."),
+    (
+        "each",
+        "map each <fn>",
+        "Calls a function with each key and value in order, returning the last result.",
+    ),
+    (
+        "map",
+        "map map <fn>",
+        "Returns a map of each key to what a function returns for the key and its value.",
+    ),
+    (
+        "filter",
+        "map filter <fn>",
+        "Returns a map of the keys and values that a function returns true for.",
+    ),
+    (
+        "fold",
+        "map fold <init> <fn>",
+        "Calls a function with what it returned last, starting with `init`, \
+         and each key and value, returning its last result.",
+    ),
+    ("keys", "map keys", "Returns a list of the keys, in order."),
+    (
+        "values",
+        "map values",
+        "Returns a list of the values, in order.",
+    ),
+    (
+        "sort",
+        "map sort",
+        "Puts the keys in sorted order, strings first and then bytes.",
+    ),
+];
+
+fn map_usage(method: &str) -> &'static str {
+    MAP_METHODS
+        .iter()
+        .find(|(name, ..)| *name == method)
+        .map_or("map <method> ...", |(_, usage, _)| usage)
+}
+
+/// Methods that lists are called with, as in `l get 0`: names, usages and help.
+pub const LIST_METHODS: &[(&str, &str, &str)] = &[
+    ("push", "list push <value>...", "Adds values to the end."),
+    (
+        "pop",
+        "list pop",
+        "Removes the last value and returns it, failing if there's none.",
+    ),
+    (
+        "get",
+        "list get <index>",
+        "Returns the value at an index, counting from zero.",
+    ),
+    (
+        "set",
+        "list set <index> <value>",
+        "Replaces the value at an index.",
+    ),
+    ("len", "list len", "Returns the number of values."),
+    (
+        "slice",
+        "list slice <start> [<end>]",
+        "Returns a list of the values from `start` up to, but not including, `end`.",
+    ),
+    (
+        "each",
+        "list each <fn>",
+        "Calls a function with each value in order, returning the last result.",
+    ),
+];
+
+fn list_usage(method: &str) -> &'static str {
+    LIST_METHODS
+        .iter()
+        .find(|(name, ..)| *name == method)
+        .map_or("list <method> ...", |(_, usage, _)| usage)
+}
+
 pub struct Frame {
-    pub variables: HashMap<String, Gc<Value>>,
+    /// In the order they were defined in.
+    pub variables: OrderedMap<String, Gc<Value>>,
 }
 
 pub struct Stack {
@@ -81,37 +269,115 @@ impl gc::Collect for Frame {
 pub struct Env {
     pub gc: gc::Heap,
     pub stack: Gc<Stack>,
+    /// Macros defined so far, expanded by [Macros::expand] before evaluation.
+    pub macros: Macros,
+    /// Whether programs that exit with a non-zero status or are killed throw their status,
+    /// like with `set -e` in other shells.
+    pub errexit: bool,
     // strings: Strings,
 }
 
-pub fn print_error(e: Vec<String>) {
-    print!("error: ");
-    e.iter().for_each(|v| println!("{v}"));
+/// The reason evaluation stopped early.
+#[derive(Debug)]
+pub enum Error {
+    /// A hard error, like a failed lookup or bad arguments. It can't be caught.
+    /// Boxed to keep results small.
+    Fail(Box<Failure>),
+    /// A value thrown by `throw`, rooted until it is caught by `catch`.
+    Throw(Gc<Value>),
+}
+
+#[derive(Debug)]
+pub struct Failure {
+    pub message: String,
+    /// The innermost command that failed.
+    pub span: Option<Span>,
+    /// The innermost builtin that failed, if the failure happened in one.
+    pub builtin: Option<String>,
+    /// Closure calls the failure propagated through, innermost first.
+    pub trace: Vec<Call>,
+}
+
+#[derive(Debug)]
+pub struct Call {
+    /// The name the closure was called by, if it was called by name.
+    pub name: Option<String>,
+    pub span: Span,
+}
+
+impl Error {
+    pub fn fail(message: impl Into<String>) -> Self {
+        Error::Fail(Box::new(Failure {
+            message: message.into(),
+            span: None,
+            builtin: None,
+            trace: Vec::new(),
+        }))
+    }
+
+    // Records the failing command, unless a more precise one is already known.
+    fn at(mut self, at: Span) -> Self {
+        if let Error::Fail(failure) = &mut self
+            && failure.span.is_none()
+        {
+            failure.span = Some(at);
+        }
+        self
+    }
+
+    // Records the builtin that failed, unless the failure comes from code it evaluated.
+    fn in_builtin(mut self, name: &str) -> Self {
+        if let Error::Fail(failure) = &mut self
+            && failure.span.is_none()
+            && failure.builtin.is_none()
+        {
+            failure.builtin = Some(name.into());
+        }
+        self
+    }
+
+    fn called_from(mut self, name: Option<&str>, span: Span) -> Self {
+        if let Error::Fail(failure) = &mut self {
+            failure.trace.push(Call {
+                name: name.map(String::from),
+                span,
+            });
+        }
+        self
+    }
 }
 
 impl Env {
     pub fn print_value(&self, v: gc::Gc<Value>) {
+        fn print_bytes(bytes: &[u8]) {
+            let hex: Vec<_> = bytes.iter().map(|b| format!("{b:02x}")).collect();
+            print!("<bytes: {}>", hex.join(" "));
+        }
         fn rec(env: &Env, v: gc::Gc<Value>, mut depth: usize, in_map: bool) {
             match env.gc.get(v) {
                 Value::String(s) => {
-                    let expr = Expr::String(s.into());
+                    let expr = Expr::String(s.into(), Span::default());
                     let mut output = String::new();
                     expr.pretty(&mut output, depth);
                     print!("{output}");
                 }
-                Value::Builtin(_f) => print!("<built-in fn>"),
-                Value::Callable(Callable::Closure { code, .. }) => {
-                    let expr = Expr::Closure(code.clone());
+                Value::Bytes(bytes) => print_bytes(bytes),
+                Value::Redirect(redirect) => print!("<redirect: {redirect}>"),
+                Value::Number(n) => print!("{n}"),
+                Value::Builtin(b) => print!("<builtin: {}>", b.usage),
+                Value::Callable(Callable::Closure { code, lazy, .. }) => {
+                    let expr = Expr::Closure(code.clone(), Span::default());
                     let mut output = String::new();
                     expr.pretty(&mut output, depth);
-                    print!("<closure: {output}>");
+                    let lazy = if *lazy { "lazy " } else { "" };
+                    print!("<{lazy}closure: {output}>");
                 }
-                Value::Exception(e) => {
-                    print!("<exception: ");
-                    rec(env, *e, depth, in_map);
-                    print!(">");
+                Value::Code(Code { expr, .. }) => {
+                    let mut output = String::new();
+                    expr.pretty(&mut output, depth);
+                    print!("<code: {output}>");
                 }
-                Value::LazyBuiltin(_) => print!("<lazy fn>"),
+                Value::LazyBuiltin(b) => print!("<lazy builtin: {}>", b.usage),
                 Value::Map(m) => {
                     if !in_map {
                         for _ in 0..depth {
@@ -124,7 +390,12 @@ impl Env {
                         for _ in 0..depth {
                             print!("    ")
                         }
-                        print!("{k}: ");
+                        match k {
+                            Key::String(k) => print!("{k}"),
+                            Key::Bytes(k) => print_bytes(k),
+                            Key::Value(k) => rec(env, *k, depth, true),
+                        }
+                        print!(": ");
                         rec(env, *v, depth, true);
                         println!(",");
                     }
@@ -134,80 +405,521 @@ impl Env {
                     }
                     print!("}}");
                 }
+                Value::List(l) => {
+                    if !in_map {
+                        for _ in 0..depth {
+                            print!("    ")
+                        }
+                    }
+                    println!("[");
+                    depth += 1;
+                    for v in l {
+                        for _ in 0..depth {
+                            print!("    ")
+                        }
+                        rec(env, *v, depth, true);
+                        println!(",");
+                    }
+                    depth -= 1;
+                    for _ in 0..depth {
+                        print!("    ")
+                    }
+                    print!("]");
+                }
             }
         }
         rec(self, v, 0, false);
         println!();
     }
 
+    /// What `help` shows: every builtin and map method if `name` is `None`,
+    /// and the details of a builtin or closure otherwise.
+    pub fn help(&mut self, name: Option<&str>) -> Result<String> {
+        let Some(name) = name else {
+            // Inner variables shadow outer ones.
+            let mut variables = BTreeMap::new();
+            let mut maybe_stack = Some(self.stack);
+            while let Some(stack) = maybe_stack {
+                let stack = self.gc.get(stack);
+                for (k, &v) in &stack.frame.variables {
+                    variables.entry(k.as_str()).or_insert(v);
+                }
+                maybe_stack = stack.up;
+            }
+            let mut builtins = Vec::new();
+            let mut closures = Vec::new();
+            for (k, v) in variables {
+                match self.gc.get(v) {
+                    Value::Builtin(b) => builtins.push(&b.usage),
+                    Value::LazyBuiltin(b) => builtins.push(&b.usage),
+                    Value::Callable(Callable::Closure { doc: Some(_), .. }) => closures.push(k),
+                    _ => (),
+                }
+            }
+            let mut text = String::from("Builtins:\n");
+            for usage in builtins {
+                text.push_str(&format!("    {usage}\n"));
+            }
+            text.push_str("Map methods:\n");
+            for (_, usage, _) in MAP_METHODS {
+                text.push_str(&format!("    {usage}\n"));
+            }
+            text.push_str("List methods:\n");
+            for (_, usage, _) in LIST_METHODS {
+                text.push_str(&format!("    {usage}\n"));
+            }
+            if !closures.is_empty() {
+                text.push_str(&format!(
+                    "Documented closures:\n    {}\n",
+                    closures.join(" ")
+                ));
+            }
+            text.push_str("Use `help <name>` for details.\n");
+            return Ok(text);
+        };
+        let Some(value) = self.lookup(name) else {
+            return Err(Error::fail(format!("help: `{name}` isn't defined")));
+        };
+        let mut text = match self.gc.get(value) {
+            Value::Builtin(b) => format!("{}\n    {}\n", b.usage, b.help),
+            Value::LazyBuiltin(b) => format!(
+                "{}\n    {}\n    It's lazy: its arguments are passed unevaluated.\n",
+                b.usage, b.help
+            ),
+            Value::Callable(Callable::Closure {
+                doc: Some(doc),
+                lazy,
+                ..
+            }) => {
+                let lazy = if *lazy {
+                    "    It's lazy: its arguments are passed as code.\n"
+                } else {
+                    ""
+                };
+                format!("{name}\n    {doc}\n{lazy}")
+            }
+            Value::Callable(_) => {
+                format!("`{name}` is a closure without documentation, see `help doc`.\n")
+            }
+            Value::String(_)
+            | Value::Bytes(_)
+            | Value::Redirect(_)
+            | Value::Number(_)
+            | Value::Code(_)
+            | Value::Map(_)
+            | Value::List(_) => {
+                format!("`{name}` isn't a function.\n")
+            }
+        };
+        let methods = match name {
+            "map" => MAP_METHODS,
+            "list" => LIST_METHODS,
+            _ => &[],
+        };
+        if !methods.is_empty() {
+            text.push_str("Methods:\n");
+            for (_, usage, help) in methods {
+                text.push_str(&format!("    {usage}\n        {help}\n"));
+            }
+        }
+        Ok(text)
+    }
+
     pub fn new(strategy: gc::Strategy) -> Self {
         let mut gc = gc::Heap::new(strategy);
 
-        let builtins: &[(_, Builtin)] = &[
-            ("set", builtins::set),
-            ("get", builtins::get),
-            ("val", builtins::val),
-            ("var", builtins::var),
-            ("del", builtins::del),
-            ("inc", builtins::inc),
-            ("+", builtins::add),
-            ("*", builtins::mul),
-            ("=", builtins::equal),
-            ("<", builtins::less),
-            (">", builtins::more),
-            ("..", builtins::concat),
-            ("!=", builtins::not_equal),
-            ("throw", builtins::throw),
-            ("println", builtins::println),
-            ("print", builtins::print),
-            ("map", builtins::map),
-            ("fail", builtins::fail),
-            ("apply", builtins::apply),
-            ("unix", builtins::unix),
-            ("lines", builtins::lines),
-            ("vars", builtins::vars),
-            ("or", builtins::or),
-            ("and", builtins::and),
+        let builtins: &[(_, _, _, EagerFn)] = &[
+            (
+                "set",
+                "set <name> <value>",
+                "Changes the value of a variable that exists.",
+                builtins::set,
+            ),
+            (
+                "get",
+                "get <name>",
+                "Returns the value of a variable. `$name` is short for `$(get name)`.",
+                builtins::get,
+            ),
+            ("val", "val <value>", "Returns its argument.", builtins::val),
+            (
+                "var",
+                "var { <name> <value> }",
+                "Defines variables in the current scope.",
+                builtins::var,
+            ),
+            ("del", "del <name>", "Removes a variable.", builtins::del),
+            (
+                "inc",
+                "inc <number>",
+                "Adds one to a number.",
+                builtins::inc,
+            ),
+            (
+                "+",
+                "+ <number>...",
+                "Adds numbers, failing on integer overflow.",
+                builtins::add,
+            ),
+            (
+                "-",
+                "- <number> <number>...",
+                "Subtracts the rest of the numbers from the first, or negates a number.",
+                builtins::sub,
+            ),
+            (
+                "*",
+                "* <number>...",
+                "Multiplies numbers, failing on integer overflow.",
+                builtins::mul,
+            ),
+            (
+                "/",
+                "/ <a: number> <b: number>",
+                "Divides numbers. Integers divide to an integer, rounded towards zero.",
+                builtins::div,
+            ),
+            (
+                "%",
+                "% <a: number> <b: number>",
+                "Returns the remainder of dividing numbers, with the sign of `a`.",
+                builtins::rem,
+            ),
+            (
+                "**",
+                "** <base: number> <exponent: number>",
+                "Raises a number to a power.",
+                builtins::pow,
+            ),
+            (
+                "abs",
+                "abs <number>",
+                "Returns the absolute value of a number.",
+                builtins::abs,
+            ),
+            (
+                "min",
+                "min <number> <number>...",
+                "Returns the least number.",
+                builtins::min,
+            ),
+            (
+                "max",
+                "max <number> <number>...",
+                "Returns the greatest number.",
+                builtins::max,
+            ),
+            (
+                "round",
+                "round <number>",
+                "Rounds a number to the closest integer, away from zero on a tie.",
+                builtins::round,
+            ),
+            (
+                "floor",
+                "floor <number>",
+                "Rounds a number down to an integer.",
+                builtins::floor,
+            ),
+            (
+                "ceil",
+                "ceil <number>",
+                "Rounds a number up to an integer.",
+                builtins::ceil,
+            ),
+            (
+                "=",
+                "= <a> <b>",
+                "Whether two strings, numbers, or lists of equal values are equal.",
+                builtins::equal,
+            ),
+            (
+                "<",
+                "< <a: number> <b: number>",
+                "Whether a number is less than another.",
+                builtins::less,
+            ),
+            (
+                ">",
+                "> <a: number> <b: number>",
+                "Whether a number is greater than another.",
+                builtins::more,
+            ),
+            (
+                "<=",
+                "<= <a: number> <b: number>",
+                "Whether a number is less than or equal to another.",
+                builtins::less_or_equal,
+            ),
+            (
+                ">=",
+                ">= <a: number> <b: number>",
+                "Whether a number is greater than or equal to another.",
+                builtins::more_or_equal,
+            ),
+            (
+                "..",
+                ".. <string>...",
+                "Concatenates strings.",
+                builtins::concat,
+            ),
+            (
+                "!=",
+                "!= <a> <b>",
+                "Whether two values aren't equal, as `=` compares them.",
+                builtins::not_equal,
+            ),
+            (
+                "throw",
+                "throw <value>",
+                "Throws a value, to be caught by `catch`.",
+                builtins::throw,
+            ),
+            (
+                "println",
+                "println <value>...",
+                "Prints values, each on its own line.",
+                builtins::println,
+            ),
+            (
+                "print",
+                "print <value>...",
+                "Prints values.",
+                builtins::print,
+            ),
+            (
+                "map",
+                "map { <key> <value> }",
+                "Makes a map of the keys to the values, in the order they're given.",
+                builtins::map,
+            ),
+            (
+                "list",
+                "list <value>...",
+                "Makes a list of the values.",
+                builtins::list,
+            ),
+            (
+                "fail",
+                "fail",
+                "Fails with an error that can't be caught.",
+                builtins::fail,
+            ),
+            (
+                "apply",
+                "apply <fn> <arg>...",
+                "Calls a function with the arguments.",
+                builtins::apply,
+            ),
+            (
+                "unix",
+                "unix <program> <arg>...",
+                "Runs a program and returns what it printed, as bytes if it isn't UTF-8. \
+                 Redirections among its arguments, like `< file`, `> file`, `>> file`, \
+                 `2> file`, `2>> file` and `2>&1`, redirect its input and output.",
+                builtins::unix,
+            ),
+            (
+                "run",
+                "run <program> <arg>...",
+                "Runs a program like `unix` does and returns a map of its `stdout`, its `stderr`, \
+                 and its exit `code` or the `signal` that killed it, even after `errexit true`.",
+                builtins::run,
+            ),
+            (
+                "redirect",
+                "redirect <op: < | > | >> | 2> | 2>> | 2>&1> <file>?",
+                "Makes a redirection of a program's input or output, which `unix`, `run`, \
+                 `pipe` and programs run by name take among their arguments. \
+                 An unquoted `> file` in a command is `$(redirect '>' file)`.",
+                builtins::redirect,
+            ),
+            (
+                "errexit",
+                "errexit <true | false>",
+                "Sets whether programs that exit with a non-zero status or are killed throw \
+                 their status, like `run` returns it, as `set -e` does in other shells. \
+                 `run` itself still returns it.",
+                builtins::errexit,
+            ),
+            (
+                "encode",
+                "encode <string>",
+                "Returns the UTF-8 bytes of a string.",
+                builtins::encode,
+            ),
+            (
+                "decode",
+                "decode <bytes> <lossy>?",
+                "Reads UTF-8 bytes as a string. Bytes that aren't UTF-8 fail, \
+                 unless `lossy` is given, which replaces them with �. A string is returned as is.",
+                builtins::decode,
+            ),
+            (
+                "pipe",
+                "pipe <stage>...",
+                "Runs a pipeline and returns what its last stage printed. A stage is a program, \
+                 a list of a program and its arguments with redirections like `unix` takes, \
+                 or a function, which is called with what the stage before printed and returns \
+                 what the stage after reads, a string or bytes.",
+                builtins::pipe,
+            ),
+            (
+                "lines",
+                "lines <string>",
+                "Splits a string into a list of lines.",
+                builtins::lines,
+            ),
+            (
+                "vars",
+                "vars",
+                "Returns a map of every variable in scope, innermost scopes first.",
+                builtins::vars,
+            ),
+            (
+                "help",
+                "help [<name>]",
+                "Lists builtins, or describes a builtin or a documented closure.",
+                builtins::help,
+            ),
+            (
+                "doc",
+                "doc <text> <closure>",
+                "Attaches documentation for `help` to a closure and returns it.",
+                builtins::doc,
+            ),
+            (
+                "lazy",
+                "lazy <closure>",
+                "Returns a copy of a closure that gets its arguments as code, for `eval`.",
+                builtins::lazy,
+            ),
+            (
+                "eval",
+                "eval <code>",
+                "Evaluates code that a lazy closure got, in the scope of its caller.",
+                builtins::eval,
+            ),
+            (
+                "or",
+                "or <boolean>...",
+                "Whether any of the arguments is true.",
+                builtins::or,
+            ),
+            (
+                "and",
+                "and <boolean>...",
+                "Whether all of the arguments are true.",
+                builtins::and,
+            ),
         ];
 
-        let lazy_builtins: &[(_, LazyBuiltin)] = &[
-            ("repeat", builtins::repeat),
-            ("catch", builtins::catch),
-            ("if", builtins::cond),
-            ("assert", builtins::assert),
+        let lazy_builtins: &[(_, _, _, LazyFn)] = &[
+            (
+                "repeat",
+                "repeat <body>",
+                "Evaluates the body over and over, until something is thrown.",
+                builtins::repeat,
+            ),
+            (
+                "catch",
+                "catch <body>",
+                "Evaluates the body, returning what's thrown in it instead of propagating it.",
+                builtins::catch,
+            ),
+            (
+                "if",
+                "if <cond> <then> <else>",
+                "Evaluates `then` if the condition is true, and `else` otherwise.",
+                builtins::cond,
+            ),
+            (
+                "assert",
+                "assert <condition>",
+                "Fails unless the condition evaluates to true.",
+                builtins::assert,
+            ),
+            (
+                "macro",
+                "macro <name> <param>... (<template>)",
+                "Defines a macro: later commands starting with its name are replaced \
+                 by the template, with `$param` replaced by the arguments.",
+                builtins::macro_,
+            ),
+            (
+                "macroexpand",
+                "macroexpand (<code>)",
+                "Returns code with the macros in it expanded.",
+                builtins::macroexpand,
+            ),
         ];
 
-        let mut variables = Vec::new();
+        let stack = gc.rooted(Stack {
+            frame: Frame {
+                variables: OrderedMap::new(),
+            },
+            up: None,
+        });
 
-        variables.extend(
-            builtins
-                .iter()
-                .map(|(k, v)| ((*k).into(), gc.rooted(Value::Builtin(*v)))),
-        );
+        let mut env = Env {
+            gc,
+            stack,
+            macros: Macros::default(),
+            errexit: false,
+        };
 
-        variables.extend(
-            lazy_builtins
-                .iter()
-                .map(|(k, v)| ((*k).into(), gc.rooted(Value::LazyBuiltin(*v)))),
-        );
+        for &(name, usage, help, f) in builtins {
+            env.register(Builtin::new(name, usage, help, f));
+        }
 
-        let variables = HashMap::from_iter(variables);
+        for &(name, usage, help, f) in lazy_builtins {
+            env.register_lazy(Builtin::lazy(name, usage, help, f));
+        }
 
-        let stack = gc.rooted(Stack {
-            frame: Frame { variables },
-            up: None,
-        });
+        env
+    }
+
+    /// Defines a builtin by its name in the outermost scope,
+    /// so that it's visible everywhere it isn't shadowed.
+    /// Arguments are evaluated before the builtin is called.
+    pub fn register(
+        &mut self,
+        builtin: Builtin<impl Fn(&mut Env, &[Gc<Value>]) -> Result + 'static>,
+    ) {
+        let name = builtin.name.clone();
+        self.define_global(&name, Value::Builtin(Rc::new(builtin)));
+    }
+
+    /// Like [Env::register], but the builtin gets its arguments as unevaluated expressions,
+    /// which can be evaluated in the caller's scope with [Env::eval_expr].
+    pub fn register_lazy(
+        &mut self,
+        builtin: Builtin<impl Fn(&mut Env, &[syntax::Expr]) -> Result + 'static>,
+    ) {
+        let name = builtin.name.clone();
+        self.define_global(&name, Value::LazyBuiltin(Rc::new(builtin)));
+    }
 
-        gc.get(stack)
+    /// Evaluates code in the scope it came from.
+    pub fn eval_code(&mut self, code: &Code) -> Result {
+        let stack = self.stack;
+        self.stack = code.stack;
+        let result = self.eval_expr(&code.expr);
+        self.stack = stack;
+        result
+    }
+
+    fn define_global(&mut self, name: &str, value: Value) {
+        let mut global = self.stack;
+        while let Some(up) = self.gc.get(global).up {
+            global = up;
+        }
+        let value = self.gc.alloc(value);
+        self.gc
+            .get_mut(global)
             .frame
             .variables
-            .values()
-            .copied()
-            .collect::<Vec<_>>()
-            .into_iter()
-            .for_each(|var| gc.unroot(var));
-
-        Env { gc, stack }
+            .insert(name.into(), value);
     }
 
     pub fn lookup(&mut self, name: &str) -> Option<Gc<Value>> {
@@ -259,104 +971,64 @@ impl Env {
                     map map fun
                 */
                 let [command, ref rest @ ..] = tail_values[..] else {
-                    return Err(vec!["map: <command> ...".into()]);
+                    return Err(Error::fail("map <method> ..."));
                 };
                 let Value::String(command) = self.gc.get(command) else {
-                    return Err(vec!["map: <command: string> ...".into()]);
+                    return Err(Error::fail("map <method: string> ..."));
                 };
                 match command.as_str() {
-                    // Doesn't handle exceptions properly.
-                    // "each" => {
-                    //     let [fun] = rest else {
-                    //         return Err(vec!["map each <fn>".into()]);
-                    //     };
-                    //     // Cloning is expensive...
-                    //     let mut result = None;
-                    //     for (k, v) in map.clone() {
-                    //         if let Some(result) = result {
-                    //             self.gc.unroot(result);
-                    //         }
-                    //         let args = vec![self.gc.rooted(Value::String(k)), self.gc.root(v)];
-                    //         self.gc.root(*fun);
-                    //         result = Some(self.apply_cmd(*fun, &args)?);
-                    //     }
-                    //     self.gc.unroot(head);
-                    //     for &v in tail_values {
-                    //         self.gc.unroot(v);
-                    //     }
-                    //     Ok(result.unwrap_or_else(|| self.gc.rooted(Value::String("ok".into()))))
-                    // }
+                    "each" | "map" | "filter" | "fold" => {
+                        let method = command.clone();
+                        let result = self.apply_map_fn(head, &method, rest);
+                        self.gc.unroot(head);
+                        for &r in tail_values {
+                            self.gc.unroot(r);
+                        }
+                        result
+                    }
                     "keys" => {
-                        let mut result = BTreeMap::new();
+                        let mut result = Vec::new();
                         // Expensive!
-                        let map = map.clone();
-                        for (i, k) in map.keys().cloned().enumerate() {
-                            result.insert(i.to_string(), self.gc.rooted(Value::String(k.clone())));
+                        let keys: Vec<_> = map.keys().cloned().collect();
+                        for k in keys {
+                            result.push(self.key_value(k));
                         }
                         self.gc.unroot(head);
                         for &r in tail_values {
                             self.gc.unroot(r);
                         }
-                        result.values().for_each(|&value| self.gc.unroot(value));
-                        Ok(self.gc.rooted(Value::Map(result)))
+                        let list = self.gc.rooted(Value::List(result.clone()));
+                        result.iter().for_each(|&value| self.gc.unroot(value));
+                        Ok(list)
                     }
                     "values" => {
-                        let mut result = BTreeMap::new();
-                        // Expensive!
-                        let map = map.clone();
-                        for (i, v) in map.values().cloned().enumerate() {
-                            result.insert(i.to_string(), v);
+                        let result = map.values().cloned().collect();
+                        self.gc.unroot(head);
+                        for &r in tail_values {
+                            self.gc.unroot(r);
                         }
+                        Ok(self.gc.rooted(Value::List(result)))
+                    }
+                    "sort" => {
+                        let [] = rest else {
+                            return Err(Error::fail(map_usage("sort")));
+                        };
+                        let Value::Map(map) = self.gc.get_mut(head) else {
+                            unreachable!()
+                        };
+                        map.sort_by(Key::sort);
                         self.gc.unroot(head);
                         for &r in tail_values {
                             self.gc.unroot(r);
                         }
-                        Ok(self.gc.rooted(Value::Map(result)))
+                        Ok(self.gc.rooted(Value::String("ok".into())))
                     }
-                    // "map" => {
-                    //     let &[fun] = rest else {
-                    //         return Err(vec!["map map <fn>".into()]);
-                    //     };
-                    //     let mut result = BTreeMap::new();
-                    //     // Cloning is expensive...
-                    //     let mut exception = None;
-                    //     for (k, v) in map.clone() {
-                    //         self.gc.root(fun);
-                    //         self.gc.root(v);
-                    //         let v = self.apply_cmd(fun, &[v])?;
-                    //         // self.gcu
-                    //         result.insert(k, v);
-                    //         // Exception checking shouldn't be manual!
-                    //         if let Value::Exception(_) = self.gc.get(v) {
-                    //             exception = Some(v);
-                    //             break
-                    //         }
-                    //     }
-
-                    //     for &v in map.values() {
-                    //         self.gc.unroot(v);
-                    //     }
-
-                    //     self.gc.unroot(head);
-                    //     for &v in tail_values {
-                    //         self.gc.unroot(v);
-                    //     }
-
-                    //     if let Some(exception) = exception {
-                    //         Ok(exception)
-                    //     } else {
-                    //         Ok(self.gc.rooted(Value::Map(result)))
-                    //     }
-                    // }
                     "get" => {
                         let [k] = rest else {
-                            return Err(vec!["map get <key>".into()]);
-                        };
-                        let Value::String(k) = self.gc.get(*k) else {
-                            return Err(vec!["map del <key: string>".into()]);
+                            return Err(Error::fail(map_usage("get")));
                         };
-                        let Some(&v) = map.get(k) else {
-                            return Err(vec!["map get: key not found".into()]);
+                        let Some(&v) = map.get(&self.key(*k)) else {
+                            return Err(Error::fail("map get: key not found"));
                         };
                         self.gc.unroot(head);
                         for &r in tail_values {
@@ -368,12 +1040,9 @@ impl Env {
                     }
                     "del" => {
                         let [k] = rest else {
-                            return Err(vec!["map del <key>".into()]);
+                            return Err(Error::fail(map_usage("del")));
                         };
-                        let Value::String(k) = self.gc.get(*k) else {
-                            return Err(vec!["map del <key: string>".into()]);
-                        };
-                        let k = k.clone();
+                        let k = self.key(*k);
                         let Value::Map(map) = self.gc.get_mut(head) else {
                             unreachable!()
                         };
@@ -386,12 +1055,9 @@ impl Env {
                     }
                     "has" => {
                         let [k] = rest else {
-                            return Err(vec!["map has <key>".into()]);
-                        };
-                        let Value::String(k) = self.gc.get(*k) else {
-                            return Err(vec!["map del <key: string>".into()]);
+                            return Err(Error::fail(map_usage("has")));
                         };
-                        let has = map.contains_key(k);
+                        let has = map.contains_key(&self.key(*k));
                         self.gc.unroot(head);
                         for &r in tail_values {
                             self.gc.unroot(r)
@@ -402,12 +1068,9 @@ impl Env {
                     }
                     "set" => {
                         let [k, v] = rest else {
-                            return Err(vec!["map set <key> <value>".into()]);
-                        };
-                        let Value::String(k) = self.gc.get(*k) else {
-                            return Err(vec!["map del <key: string>".into()]);
+                            return Err(Error::fail(map_usage("set")));
                         };
-                        let k = k.clone();
+                        let k = self.key(*k);
                         let Value::Map(map) = self.gc.get_mut(head) else {
                             unreachable!()
                         };
@@ -418,12 +1081,26 @@ impl Env {
                         }
                         Ok(self.gc.rooted(Value::String("ok".into())))
                     }
-                    _ => Err(vec!["map: unknown command".into()]),
+                    _ => Err(Error::fail("map: unknown method, see `help map`")),
                 }
             }
-            Value::String(_) => Err(vec!["cmd's fn must not be a string".into()]),
-            Value::Builtin(f) => {
-                let result = f(self, tail_values);
+            Value::List(_) => {
+                let result = self.apply_list(head, tail_values);
+                self.gc.unroot(head);
+                for &r in tail_values {
+                    self.gc.unroot(r);
+                }
+                result
+            }
+            Value::String(_) => Err(Error::fail("cmd's fn must not be a string")),
+            Value::Bytes(_) => Err(Error::fail("cmd's fn must not be bytes")),
+            Value::Redirect(_) => Err(Error::fail("cmd's fn must not be a redirection")),
+            Value::Number(_) => Err(Error::fail("cmd's fn must not be a number")),
+            Value::Code(_) => Err(Error::fail("cmd's fn must not be code, see `help eval`")),
+            Value::Builtin(builtin) => {
+                let builtin = builtin.clone();
+                let result =
+                    (builtin.f)(self, tail_values).map_err(|e| e.in_builtin(&builtin.name));
                 // unroot here on in builtins?
                 for &e in tail_values {
                     self.gc.unroot(e);
@@ -432,7 +1109,7 @@ impl Env {
                 result
             }
             // stack should be reachable via closure.
-            Value::Callable(Callable::Closure { code, stack }) => {
+            Value::Callable(Callable::Closure { code, stack, .. }) => {
                 let result = self.eval_closure(code.clone(), *stack, tail_values);
                 for &e in tail_values {
                     self.gc.unroot(e);
@@ -440,31 +1117,278 @@ impl Env {
                 self.gc.unroot(head);
                 result
             }
-            // _ => todo!()
-            // Idk if this is correct.
-            Value::Exception(_) => {
-                // let value = *value;
-                for &e in tail_values {
-                    self.gc.unroot(e);
+            Value::LazyBuiltin(builtin) => Err(Error::fail(format!(
+                "`{}` is lazy and can't be applied to values",
+                builtin.name
+            ))),
+        }
+    }
+
+    /// The key that a value is looked up by in a map.
+    pub fn key(&self, value: Gc<Value>) -> Key {
+        match self.gc.get(value) {
+            Value::String(s) => Key::String(s.clone()),
+            Value::Bytes(b) => Key::Bytes(b.clone()),
+            // So that `2`, `$(+ 1 1)` and `$(* 1 2.0)` are the same key.
+            Value::Number(Number::Float(f)) if f.fract() == 0.0 => {
+                let n = Number::Float(*f).to_integer(f64::trunc);
+                Key::String(n.expect("a float without a fraction is finite").to_string())
+            }
+            Value::Number(n) => Key::String(n.to_string()),
+            _ => Key::Value(value),
+        }
+    }
+
+    // The value of a key, rooted.
+    fn key_value(&mut self, key: Key) -> Gc<Value> {
+        match key {
+            Key::String(s) => self.gc.rooted(Value::String(s)),
+            Key::Bytes(b) => self.gc.rooted(Value::Bytes(b)),
+            Key::Value(value) => self.gc.root(value),
+        }
+    }
+
+    // Methods that call a function with each key and value of a map.
+    // Doesn't unroot the map and the arguments, unlike apply_cmd.
+    fn apply_map_fn(&mut self, head: Gc<Value>, method: &str, rest: &[Gc<Value>]) -> Result {
+        let (init, fun) = match (method, rest) {
+            ("fold", &[init, fun]) => (Some(init), fun),
+            ("each" | "map" | "filter", &[fun]) => (None, fun),
+            _ => return Err(Error::fail(map_usage(method))),
+        };
+        let Value::Map(map) = self.gc.get(head) else {
+            unreachable!()
+        };
+        // The function may change the map, so entries are rooted while it's iterated.
+        let entries: Vec<_> = map.iter().map(|(k, &v)| (k.clone(), v)).collect();
+        let entries: Vec<_> = entries
+            .into_iter()
+            .map(|(key, v)| {
+                let k = self.key_value(key.clone());
+                (key, k, self.gc.root(v))
+            })
+            .collect();
+        // Everything in here is rooted: the last result, the accumulator and new values.
+        let mut last = init.map(|init| self.gc.root(init));
+        let mut result = OrderedMap::new();
+        let mut error = None;
+        for (key, k, v) in &entries {
+            let mut args = vec![*k, *v];
+            if method == "fold" {
+                args.insert(0, last.unwrap());
+            }
+            // apply_cmd unroots them.
+            self.gc.root(fun);
+            for &arg in &args {
+                self.gc.root(arg);
+            }
+            let value = match self.apply_cmd(fun, &args) {
+                Ok(value) => value,
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            };
+            match method {
+                "map" => {
+                    result.insert(key.clone(), value);
+                }
+                "filter" => {
+                    let keep = match self.gc.get(value) {
+                        Value::String(s) if s == "true" => Some(true),
+                        Value::String(s) if s == "false" => Some(false),
+                        _ => None,
+                    };
+                    self.gc.unroot(value);
+                    match keep {
+                        Some(true) => {
+                            result.insert(key.clone(), self.gc.root(*v));
+                        }
+                        Some(false) => (),
+                        None => {
+                            let e = "map filter: the function must return true or false";
+                            error = Some(Error::fail(e));
+                            break;
+                        }
+                    }
+                }
+                _ => {
+                    if let Some(last) = last.replace(value) {
+                        self.gc.unroot(last);
+                    }
+                }
+            }
+        }
+        let value = match error {
+            Some(e) => Err(e),
+            None if method == "map" || method == "filter" => {
+                Ok(self.gc.rooted(Value::Map(result.clone())))
+            }
+            None => match last {
+                Some(last) => Ok(self.gc.root(last)),
+                None => Ok(self.gc.rooted(Value::String("ok".into()))),
+            },
+        };
+        for (_, k, v) in entries {
+            self.gc.unroot(k);
+            self.gc.unroot(v);
+        }
+        for &v in result.values() {
+            self.gc.unroot(v);
+        }
+        if let Some(last) = last {
+            self.gc.unroot(last);
+        }
+        value
+    }
+
+    // Doesn't unroot the list and the arguments, unlike apply_cmd.
+    fn apply_list(&mut self, head: Gc<Value>, tail_values: &[Gc<Value>]) -> Result {
+        let Value::List(list) = self.gc.get(head) else {
+            unreachable!()
+        };
+        let [method, ref rest @ ..] = tail_values[..] else {
+            return Err(Error::fail("list <method> ..."));
+        };
+        let Value::String(method) = self.gc.get(method) else {
+            return Err(Error::fail("list <method: string> ..."));
+        };
+        let index = |arg: Gc<Value>, len: usize| {
+            let i = match self.gc.get(arg) {
+                Value::String(i) => Number::parse(i),
+                Value::Number(n) => Some(n.clone()),
+                _ => None,
+            };
+            match i {
+                Some(Number::Int(i)) if i >= 0 && (i as usize) < len => Ok(i as usize),
+                Some(i @ (Number::Int(_) | Number::Big(_))) => {
+                    Err(Error::fail(format!("list: index {i} is out of bounds")))
+                }
+                _ => Err(Error::fail("list: an index must be an integer")),
+            }
+        };
+        match method.as_str() {
+            "push" => {
+                let rest = rest.to_vec();
+                let Value::List(list) = self.gc.get_mut(head) else {
+                    unreachable!()
+                };
+                list.extend(rest);
+                Ok(self.gc.rooted(Value::String("ok".into())))
+            }
+            "pop" => {
+                let [] = rest else {
+                    return Err(Error::fail(list_usage("pop")));
+                };
+                let Value::List(list) = self.gc.get_mut(head) else {
+                    unreachable!()
+                };
+                let Some(v) = list.pop() else {
+                    return Err(Error::fail("list pop: the list is empty"));
+                };
+                Ok(self.gc.root(v))
+            }
+            "get" => {
+                let &[i] = rest else {
+                    return Err(Error::fail(list_usage("get")));
+                };
+                let v = list[index(i, list.len())?];
+                Ok(self.gc.root(v))
+            }
+            "set" => {
+                let &[i, v] = rest else {
+                    return Err(Error::fail(list_usage("set")));
+                };
+                let i = index(i, list.len())?;
+                let Value::List(list) = self.gc.get_mut(head) else {
+                    unreachable!()
+                };
+                list[i] = v;
+                Ok(self.gc.rooted(Value::String("ok".into())))
+            }
+            "len" => {
+                let [] = rest else {
+                    return Err(Error::fail(list_usage("len")));
+                };
+                let len = Number::Int(list.len() as i64);
+                Ok(self.gc.rooted(Value::Number(len)))
+            }
+            "slice" => {
+                let (start, end) = match *rest {
+                    [start] => (start, None),
+                    [start, end] => (start, Some(end)),
+                    _ => return Err(Error::fail(list_usage("slice"))),
+                };
+                // Both ends may be the length, for an empty slice at the end.
+                let start = index(start, list.len() + 1)?;
+                let end = match end {
+                    Some(end) => index(end, list.len() + 1)?,
+                    None => list.len(),
+                };
+                if start > end {
+                    return Err(Error::fail("list slice: start is after end"));
+                }
+                let slice = list[start..end].to_vec();
+                Ok(self.gc.rooted(Value::List(slice)))
+            }
+            "each" => {
+                let &[fun] = rest else {
+                    return Err(Error::fail(list_usage("each")));
+                };
+                // The function may change the list, so values are rooted while it's iterated.
+                let items = list.clone();
+                for &item in &items {
+                    self.gc.root(item);
+                }
+                let mut result = Ok(None);
+                for &item in &items {
+                    if let Ok(Some(value)) = result {
+                        self.gc.unroot(value);
+                    }
+                    // apply_cmd unroots them.
+                    self.gc.root(fun);
+                    self.gc.root(item);
+                    result = self.apply_cmd(fun, &[item]).map(Some);
+                    if result.is_err() {
+                        break;
+                    }
+                }
+                for &item in &items {
+                    self.gc.unroot(item);
+                }
+                match result? {
+                    Some(value) => Ok(value),
+                    None => Ok(self.gc.rooted(Value::String("ok".into()))),
                 }
-                Ok(head)
             }
-            Value::LazyBuiltin(_) => panic!(),
+            _ => Err(Error::fail("list: unknown method, see `help list`")),
         }
     }
 
     // Roots result
     pub fn eval_cmd(&mut self, cmd: &syntax::Command) -> Result {
-        let [head, tail @ ..] = &cmd.0[..] else {
+        self.eval_cmd_at(cmd).map_err(|e| e.at(cmd.span))
+    }
+
+    fn eval_cmd_at(&mut self, cmd: &syntax::Command) -> Result {
+        let [head, tail @ ..] = &cmd.exprs[..] else {
             panic!();
         };
 
+        let name = match head {
+            Expr::String(string, _) => Some(string.as_str()),
+            _ => None,
+        };
+
         // lookup if string
         // otherwise eval expr
         let head = match head {
-            Expr::String(string) => {
+            Expr::String(string, span) => {
                 let Some(value) = self.lookup(string) else {
-                    return Err(vec!["lookup failed".into()]);
+                    if process::is_program(string) {
+                        return self.eval_program(string, &cmd.redirected()[1..]);
+                    }
+                    return Err(Error::fail("lookup failed").at(*span));
                 };
                 // need to root so doesn't disappear during eval? idk.
                 self.gc.root(value);
@@ -473,32 +1397,56 @@ impl Env {
             _ => self.eval_expr(head)?, // rooted, need to unroot later
         };
 
-        if let Value::LazyBuiltin(l) = self.gc.get(head) {
-            let result = l(self, tail);
+        if let Value::LazyBuiltin(builtin) = self.gc.get(head) {
+            let builtin = builtin.clone();
+            let result = (builtin.f)(self, tail).map_err(|e| e.in_builtin(&builtin.name));
             self.gc.unroot(head);
             return result;
         }
 
+        let lazy = matches!(
+            self.gc.get(head),
+            Value::Callable(Callable::Closure { lazy: true, .. })
+        );
+
+        let exprs = match self.gc.get(head) {
+            Value::Builtin(builtin) => process::builtin_exprs(&builtin.name, cmd),
+            _ => Cow::Borrowed(&cmd.exprs[..]),
+        };
+        let tail = &exprs[1..];
+
         let mut tail_values = Vec::new();
 
         for e in tail {
             // all rooted, need to unroot.
-            // want to be lazier?
-
-            let v = self.eval_expr(e)?;
+            if lazy {
+                tail_values.push(self.gc.rooted(Value::Code(Code {
+                    expr: e.clone(),
+                    stack: self.stack,
+                })));
+                continue;
+            }
 
-            if let Value::Exception(_) = self.gc.get(v) {
-                for value in tail_values {
-                    self.gc.unroot(value);
+            let v = match self.eval_expr(e) {
+                Ok(v) => v,
+                Err(e) => {
+                    for value in tail_values {
+                        self.gc.unroot(value);
+                    }
+                    self.gc.unroot(head);
+                    return Err(e);
                 }
-                self.gc.unroot(head);
-                return Ok(v);
-            }
+            };
 
             tail_values.push(v);
         }
 
-        self.apply_cmd(head, &tail_values)
+        if let Value::Callable(_) = self.gc.get(head) {
+            self.apply_cmd(head, &tail_values)
+                .map_err(|e| e.at(cmd.span).called_from(name, cmd.span))
+        } else {
+            self.apply_cmd(head, &tail_values)
+        }
     }
 
     // No idea if this is correct.
@@ -514,7 +1462,7 @@ impl Env {
         // Well, maybe # should be? But isn't it reachable from the closure?
         let new_stack = self.gc.rooted(Stack {
             frame: Frame {
-                variables: HashMap::new(),
+                variables: OrderedMap::new(),
             },
             up: Some(stack),
         });
@@ -534,7 +1482,7 @@ impl Env {
 
         self.stack = new_stack;
 
-        let value = self.eval_expr(&syntax::Expr::Block(commands));
+        let value = self.eval_expr(&syntax::Expr::Block(commands, Span::default()));
 
         self.gc.unroot(new_stack);
 
@@ -546,13 +1494,13 @@ impl Env {
     // Roots result
     pub fn eval_expr(&mut self, expr: &syntax::Expr) -> Result {
         match expr {
-            syntax::Expr::String(s) => Result::Ok(self.gc.rooted(Value::String(s.to_owned()))),
-            syntax::Expr::Block(commands) => {
+            syntax::Expr::String(s, _) => Result::Ok(self.gc.rooted(Value::String(s.to_owned()))),
+            syntax::Expr::Block(commands, _) => {
                 // Not sure if rooting stack makes a difference here.
 
                 let new_stack = self.gc.rooted(Stack {
                     frame: Frame {
-                        variables: HashMap::new(),
+                        variables: OrderedMap::new(),
                     },
                     up: Some(self.stack),
                 });
@@ -563,34 +1511,36 @@ impl Env {
                 // self.gc.unroot(old_stack);
                 self.stack = new_stack;
 
-                let mut result = None;
+                let mut result = Ok(None);
 
                 for command in &commands.0 {
-                    if let Some(value) = result {
-                        if let Value::Exception(_) = self.gc.get(value) {
-                            return Ok(value);
-                        }
+                    if let Ok(Some(value)) = result {
                         self.gc.unroot(value);
                     }
-                    result = Some(self.eval_cmd(command)?);
+                    result = self.eval_cmd(command).map(Some);
+                    if result.is_err() {
+                        break;
+                    }
                 }
 
+                // The stack has to be restored even if an exception is propagating.
                 self.gc.unroot(new_stack);
                 // self.gc.root(old_stack);
                 self.stack = old_stack;
 
-                if let Some(value) = result {
-                    Ok(value)
-                } else {
-                    Ok(self.gc.rooted(Value::String("ok".into())))
+                match result? {
+                    Some(value) => Ok(value),
+                    None => Ok(self.gc.rooted(Value::String("ok".into()))),
                 }
             }
-            Expr::Closure(commands) => Ok(self.gc.rooted(Value::Callable(Callable::Closure {
+            Expr::Closure(commands, _) => Ok(self.gc.rooted(Value::Callable(Callable::Closure {
                 code: commands.clone(),
                 stack: self.stack,
+                doc: None,
+                lazy: false,
             }))),
         }
     }
 }
 
-pub type Result<T = Gc<Value>, E = Vec<String>> = std::result::Result<T, E>;
+pub type Result<T = Gc<Value>, E = Error> = std::result::Result<T, E>;
@@ -0,0 +1,317 @@
+use std::{cmp::Ordering, fmt};
+
+/// An integer of any size, for arithmetic on integers that don't fit in an i64.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BigInt {
+    // Zero isn't negative.
+    negative: bool,
+    // Base 2^32 digits, least significant first, without leading zeros.
+    magnitude: Vec<u32>,
+}
+
+impl BigInt {
+    fn new(negative: bool, mut magnitude: Vec<u32>) -> Self {
+        while magnitude.last() == Some(&0) {
+            magnitude.pop();
+        }
+        let negative = negative && !magnitude.is_empty();
+        BigInt {
+            negative,
+            magnitude,
+        }
+    }
+
+    pub fn from_i64(i: i64) -> Self {
+        let u = i.unsigned_abs();
+        BigInt::new(i < 0, vec![u as u32, (u >> 32) as u32])
+    }
+
+    pub fn to_i64(&self) -> Option<i64> {
+        if self.magnitude.len() > 2 {
+            return None;
+        }
+        let mut u = 0u64;
+        for &digit in self.magnitude.iter().rev() {
+            u = u << 32 | digit as u64;
+        }
+        let i = if self.negative {
+            -(u as i128)
+        } else {
+            u as i128
+        };
+        i64::try_from(i).ok()
+    }
+
+    /// Reads decimal digits, optionally with a sign.
+    pub fn parse(s: &str) -> Option<Self> {
+        let (negative, digits) = match s.as_bytes().first()? {
+            b'-' => (true, &s[1..]),
+            b'+' => (false, &s[1..]),
+            _ => (false, s),
+        };
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let mut magnitude = Vec::new();
+        for digit in digits.bytes() {
+            mul_add_small(&mut magnitude, 10, (digit - b'0') as u32);
+        }
+        Some(BigInt::new(negative, magnitude))
+    }
+
+    /// The integer a float is equal to, if it's finite and has no fractional part.
+    pub fn from_f64(f: f64) -> Option<Self> {
+        if !f.is_finite() || f.fract() != 0.0 {
+            return None;
+        }
+        if f.abs() < 2f64.powi(63) {
+            return Some(BigInt::from_i64(f as i64));
+        }
+        // Big floats are their mantissa shifted left by their exponent.
+        let bits = f.to_bits();
+        let exponent = ((bits >> 52) & 0x7ff) as usize - 1075;
+        let mantissa = bits & ((1 << 52) - 1) | 1 << 52;
+        let mantissa = vec![mantissa as u32, (mantissa >> 32) as u32];
+        Some(BigInt::new(f < 0.0, shift_left(&mantissa, exponent)))
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        let mut f = 0.0;
+        for &digit in self.magnitude.iter().rev() {
+            f = f * 4294967296.0 + digit as f64;
+        }
+        if self.negative { -f } else { f }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.magnitude.is_empty()
+    }
+
+    pub fn bits(&self) -> u64 {
+        match self.magnitude.last() {
+            Some(top) => self.magnitude.len() as u64 * 32 - top.leading_zeros() as u64,
+            None => 0,
+        }
+    }
+
+    pub fn neg(&self) -> Self {
+        BigInt::new(!self.negative, self.magnitude.clone())
+    }
+
+    pub fn abs(&self) -> Self {
+        BigInt::new(false, self.magnitude.clone())
+    }
+
+    pub fn add(&self, other: &BigInt) -> Self {
+        if self.negative == other.negative {
+            return BigInt::new(self.negative, add(&self.magnitude, &other.magnitude));
+        }
+        // The sign is that of the one with the bigger magnitude.
+        match compare(&self.magnitude, &other.magnitude) {
+            Ordering::Less => BigInt::new(other.negative, sub(&other.magnitude, &self.magnitude)),
+            _ => BigInt::new(self.negative, sub(&self.magnitude, &other.magnitude)),
+        }
+    }
+
+    pub fn sub(&self, other: &BigInt) -> Self {
+        self.add(&other.neg())
+    }
+
+    pub fn mul(&self, other: &BigInt) -> Self {
+        BigInt::new(
+            self.negative != other.negative,
+            mul(&self.magnitude, &other.magnitude),
+        )
+    }
+
+    /// Division rounded towards zero and its remainder, which has the sign of `self`.
+    /// None if `other` is zero.
+    pub fn div_rem(&self, other: &BigInt) -> Option<(Self, Self)> {
+        if other.is_zero() {
+            return None;
+        }
+        let (quotient, remainder) = div_rem(&self.magnitude, &other.magnitude);
+        Some((
+            BigInt::new(self.negative != other.negative, quotient),
+            BigInt::new(self.negative, remainder),
+        ))
+    }
+
+    pub fn pow(&self, mut exponent: u32) -> Self {
+        let mut result = BigInt::from_i64(1);
+        let mut base = self.clone();
+        while exponent > 0 {
+            if exponent & 1 == 1 {
+                result = result.mul(&base);
+            }
+            exponent >>= 1;
+            if exponent > 0 {
+                base = base.mul(&base);
+            }
+        }
+        result
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => compare(&self.magnitude, &other.magnitude),
+            (true, true) => compare(&other.magnitude, &self.magnitude),
+        }
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_zero() {
+            return write!(f, "0");
+        }
+        // Nine decimal digits at a time, least significant first.
+        let mut chunks = Vec::new();
+        let mut magnitude = self.magnitude.clone();
+        while !magnitude.is_empty() {
+            let (quotient, remainder) = div_rem_small(&magnitude, 1_000_000_000);
+            chunks.push(remainder);
+            magnitude = quotient;
+        }
+        if self.negative {
+            write!(f, "-")?;
+        }
+        let mut chunks = chunks.iter().rev();
+        write!(f, "{}", chunks.next().unwrap())?;
+        for chunk in chunks {
+            write!(f, "{chunk:09}")?;
+        }
+        Ok(())
+    }
+}
+
+// The functions below work on magnitudes, which may have leading zeros.
+
+fn trim(mut a: Vec<u32>) -> Vec<u32> {
+    while a.last() == Some(&0) {
+        a.pop();
+    }
+    a
+}
+
+fn compare(a: &[u32], b: &[u32]) -> Ordering {
+    let (a, b) = (trim(a.to_vec()), trim(b.to_vec()));
+    a.len()
+        .cmp(&b.len())
+        .then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+fn add(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut result = Vec::with_capacity(a.len().max(b.len()) + 1);
+    let mut carry = 0u64;
+    for i in 0..a.len().max(b.len()) {
+        let sum = *a.get(i).unwrap_or(&0) as u64 + *b.get(i).unwrap_or(&0) as u64 + carry;
+        result.push(sum as u32);
+        carry = sum >> 32;
+    }
+    result.push(carry as u32);
+    trim(result)
+}
+
+// `a` must be at least `b`.
+fn sub(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut result = Vec::with_capacity(a.len());
+    let mut borrow = 0i64;
+    for (i, &digit) in a.iter().enumerate() {
+        let mut difference = digit as i64 - *b.get(i).unwrap_or(&0) as i64 - borrow;
+        borrow = 0;
+        if difference < 0 {
+            difference += 1 << 32;
+            borrow = 1;
+        }
+        result.push(difference as u32);
+    }
+    trim(result)
+}
+
+fn mul(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut result = vec![0u32; a.len() + b.len()];
+    for (i, &x) in a.iter().enumerate() {
+        let mut carry = 0u64;
+        for (j, &y) in b.iter().enumerate() {
+            let product = x as u64 * y as u64 + result[i + j] as u64 + carry;
+            result[i + j] = product as u32;
+            carry = product >> 32;
+        }
+        result[i + b.len()] = carry as u32;
+    }
+    trim(result)
+}
+
+fn mul_add_small(a: &mut Vec<u32>, factor: u32, addend: u32) {
+    let mut carry = addend as u64;
+    for digit in a.iter_mut() {
+        let product = *digit as u64 * factor as u64 + carry;
+        *digit = product as u32;
+        carry = product >> 32;
+    }
+    if carry > 0 {
+        a.push(carry as u32);
+    }
+}
+
+fn div_rem_small(a: &[u32], divisor: u32) -> (Vec<u32>, u32) {
+    let mut quotient = vec![0u32; a.len()];
+    let mut remainder = 0u64;
+    for (i, &digit) in a.iter().enumerate().rev() {
+        let current = remainder << 32 | digit as u64;
+        quotient[i] = (current / divisor as u64) as u32;
+        remainder = current % divisor as u64;
+    }
+    (trim(quotient), remainder as u32)
+}
+
+fn shift_left(a: &[u32], bits: usize) -> Vec<u32> {
+    let mut result = vec![0u32; bits / 32];
+    let shift = bits % 32;
+    let mut carry = 0u32;
+    for &digit in a {
+        if shift == 0 {
+            result.push(digit);
+        } else {
+            result.push(digit << shift | carry);
+            carry = digit >> (32 - shift);
+        }
+    }
+    result.push(carry);
+    trim(result)
+}
+
+// Long division a bit at a time, which is slow but simple.
+fn div_rem(a: &[u32], b: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    if let [divisor] = trim(b.to_vec())[..] {
+        let (quotient, remainder) = div_rem_small(a, divisor);
+        return (quotient, vec![remainder]);
+    }
+    let mut quotient = vec![0u32; a.len()];
+    let mut remainder = Vec::new();
+    for i in (0..a.len() * 32).rev() {
+        remainder = shift_left(&remainder, 1);
+        if a[i / 32] >> (i % 32) & 1 == 1 {
+            match remainder.first_mut() {
+                Some(digit) => *digit |= 1,
+                None => remainder.push(1),
+            }
+        }
+        if compare(&remainder, b) != Ordering::Less {
+            remainder = sub(&remainder, b);
+            quotient[i / 32] |= 1 << (i % 32);
+        }
+    }
+    (trim(quotient), trim(remainder))
+}
@@ -1,21 +1,24 @@
-use std::collections::BTreeMap;
+use std::{cmp::Ordering, collections::HashSet};
 
 use gc::Gc;
 
 use crate::{
-    interpreter::{Env, Result, Value},
+    interpreter::{
+        BigInt, Callable, Code, Env, Error, Key, Number, Program, Redirect, Result, Stage, Value,
+    },
+    ordered_map::OrderedMap,
     syntax::Expr,
 };
 
 pub(crate) fn or(env: &mut Env, args: &[Gc<Value>]) -> Result {
     for &arg in args {
         let Value::String(string) = env.gc.get(arg) else {
-            return Err(vec!["or <string>...".into()]);
+            return Err(Error::fail("or <string>..."));
         };
         match string.as_ref() {
             "true" => return Ok(env.gc.rooted(Value::String("true".into()))),
             "false" => continue,
-            _ => return Err(vec!["or <value: true | false>...".into()]),
+            _ => return Err(Error::fail("or <value: true | false>...")),
         };
     }
     Ok(env.gc.rooted(Value::String("false".into())))
@@ -24,87 +27,136 @@ pub(crate) fn or(env: &mut Env, args: &[Gc<Value>]) -> Result {
 pub(crate) fn and(env: &mut Env, args: &[Gc<Value>]) -> Result {
     for &arg in args {
         let Value::String(string) = env.gc.get(arg) else {
-            return Err(vec!["and <string>...".into()]);
+            return Err(Error::fail("and <string>..."));
         };
         match string.as_ref() {
             "true" => continue,
             "false" => return Ok(env.gc.rooted(Value::String("false".into()))),
-            _ => return Err(vec!["and <value: true | false>...".into()]),
+            _ => return Err(Error::fail("and <value: true | false>...")),
         };
     }
     Ok(env.gc.rooted(Value::String("true".into())))
 }
 
-pub(crate) fn more(env: &mut Env, args: &[Gc<Value>]) -> Result {
-    let &[a, b] = args else {
-        return Err(vec!["> <a> <b>".into()]);
+pub(crate) fn vars(env: &mut Env, _args: &[Gc<Value>]) -> Result {
+    let mut result = OrderedMap::new();
+    let mut maybe_stack = Some(env.stack);
+    while let Some(stack) = maybe_stack {
+        let stack = env.gc.get(stack);
+        // Inner variables shadow outer ones.
+        for (k, &v) in &stack.frame.variables {
+            let k = Key::String(k.clone());
+            if !result.contains_key(&k) {
+                result.insert(k, v);
+            }
+        }
+        maybe_stack = stack.up;
+    }
+    Ok(env.gc.rooted(Value::Map(result)))
+}
+
+pub(crate) fn help(env: &mut Env, args: &[Gc<Value>]) -> Result {
+    let name = match args {
+        [] => None,
+        &[name] => {
+            let Value::String(name) = env.gc.get(name) else {
+                return Err(Error::fail("help [<name: string>]"));
+            };
+            Some(name.clone())
+        }
+        _ => return Err(Error::fail("help [<name>]")),
     };
-    let (a, b) = (env.gc.get(a), env.gc.get(b));
-    let (Value::String(a), Value::String(b)) = (a, b) else {
-        return Err(vec!["> <a: string> <b: string>".into()]);
+    print!("{}", env.help(name.as_deref())?);
+    Ok(env.gc.rooted(Value::String("ok".into())))
+}
+
+pub(crate) fn doc(env: &mut Env, args: &[Gc<Value>]) -> Result {
+    let &[text, closure] = args else {
+        return Err(Error::fail("doc <text> <closure>"));
     };
-    let (a, b) = (a.parse::<isize>(), b.parse::<isize>());
-    let (Ok(a), Ok(b)) = (a, b) else {
-        return Err(vec!["> <a: number> <b: number>".into()]);
+    let Value::String(text) = env.gc.get(text) else {
+        return Err(Error::fail("doc <text: string> <closure>"));
     };
-    Ok(env
-        .gc
-        .rooted(Value::String(if a > b { "true" } else { "false" }.into())))
+    let text = text.clone();
+    let Value::Callable(Callable::Closure { doc, .. }) = env.gc.get_mut(closure) else {
+        return Err(Error::fail("doc <text> <closure: closure>"));
+    };
+    *doc = Some(text);
+    env.gc.root(closure);
+    Ok(closure)
 }
 
-pub(crate) fn less(env: &mut Env, args: &[Gc<Value>]) -> Result {
-    let &[a, b] = args else {
-        return Err(vec!["< <a> <b>".into()]);
+pub(crate) fn lazy(env: &mut Env, args: &[Gc<Value>]) -> Result {
+    let &[closure] = args else {
+        return Err(Error::fail("lazy <closure>"));
     };
-    let (a, b) = (env.gc.get(a), env.gc.get(b));
-    let (Value::String(a), Value::String(b)) = (a, b) else {
-        return Err(vec!["< <a: string> <b: string>".into()]);
+    let Value::Callable(Callable::Closure {
+        code, stack, doc, ..
+    }) = env.gc.get(closure)
+    else {
+        return Err(Error::fail("lazy <closure: closure>"));
     };
-    let (a, b) = (a.parse::<isize>(), b.parse::<isize>());
-    let (Ok(a), Ok(b)) = (a, b) else {
-        return Err(vec!["< <a: number> <b: number>".into()]);
+    let closure = Callable::Closure {
+        code: code.clone(),
+        stack: *stack,
+        doc: doc.clone(),
+        lazy: true,
     };
-    Ok(env
-        .gc
-        .rooted(Value::String(if a < b { "true" } else { "false" }.into())))
+    Ok(env.gc.rooted(Value::Callable(closure)))
 }
 
-pub(crate) fn vars(env: &mut Env, _args: &[Gc<Value>]) -> Result {
-    let mut result = BTreeMap::new();
-    let mut maybe_stack = Some(env.stack);
-    while let Some(stack) = maybe_stack {
-        let stack = env.gc.get(stack);
-        for (k, &v) in &stack.frame.variables {
-            result.entry(k.clone()).or_insert(v);
-        }
-        maybe_stack = stack.up;
+pub(crate) fn eval(env: &mut Env, args: &[Gc<Value>]) -> Result {
+    let &[code] = args else {
+        return Err(Error::fail("eval <code>"));
+    };
+    let Value::Code(Code { expr, stack }) = env.gc.get(code) else {
+        return Err(Error::fail("eval <code: code>"));
+    };
+    let code = Code {
+        expr: expr.clone(),
+        stack: *stack,
+    };
+    env.eval_code(&code)
+}
+
+pub(crate) fn macro_(_env: &mut Env, _args: &[Expr]) -> Result {
+    // Definitions are taken out by Macros::expand, so this is only reached indirectly.
+    Err(Error::fail(
+        "macros are defined before the code runs, `macro` can't be called",
+    ))
+}
+
+pub(crate) fn macroexpand(env: &mut Env, args: &[Expr]) -> Result {
+    let [Expr::Closure(commands, _)] = args else {
+        return Err(Error::fail("macroexpand (<code>)"));
+    };
+    // Macros defined in the code don't outlive it.
+    let commands = env.macros.clone().expand(commands)?;
+    let mut output = Vec::new();
+    for command in &commands.0 {
+        output.push(command.to_string());
     }
-    Ok(env.gc.rooted(Value::Map(result)))
+    Ok(env.gc.rooted(Value::String(output.join("\n"))))
 }
 
 pub(crate) fn fail(_env: &mut Env, _args: &[Gc<Value>]) -> Result {
-    Err(vec!["fail".into()])
+    Err(Error::fail("fail"))
 }
 
 pub(crate) fn assert(env: &mut Env, args: &[Expr]) -> Result {
     let [arg] = args else {
-        return Err(vec!["assert <boolean: expr>".into()]);
+        return Err(Error::fail("assert <boolean: expr>"));
     };
     let value = env.eval_expr(arg)?;
-    let Value::String(boolean) = env.gc.get(value) else {
+    let holds = matches!(env.gc.get(value), Value::String(boolean) if boolean == "true");
+    env.gc.unroot(value);
+    if holds {
+        Ok(env.gc.rooted(Value::String("ok".into())))
+    } else {
         let mut pretty = String::new();
         arg.pretty(&mut pretty, 0);
         let string = format!("assertion failed {}", pretty);
-        return Err(vec![string]);
-    };
-    match boolean.as_ref() {
-        "true" => Ok(env.gc.rooted(Value::String("ok".into()))),
-        _ => {
-            let mut pretty = String::new();
-            arg.pretty(&mut pretty, 0);
-            let string = format!("assertion failed {}", pretty);
-            Err(vec![string])
-        }
+        Err(Error::fail(string))
     }
 }
 
@@ -114,120 +166,229 @@ pub(crate) fn apply(env: &mut Env, args: &[Gc<Value>]) -> Result {
         env.gc.root(arg);
     });
     let [fun, args @ ..] = args else {
-        return Err(vec!["apply <fn> <args>...".into()]);
+        return Err(Error::fail("apply <fn> <args>..."));
     };
     env.apply_cmd(*fun, args)
 }
 
 pub(crate) fn unix(env: &mut Env, args: &[Gc<Value>]) -> Result {
-    let args: Vec<String> = args
-        .iter()
-        .map(|&arg| {
-            let Value::String(s) = env.gc.get(arg) else {
-                return Err(vec!["unix <string>...".into()]);
-            };
-            Ok(s.to_owned())
-        })
-        .collect::<Result<_>>()?;
-    let [head, rest @ ..] = &args[..] else {
-        return Err(vec!["unix cmd <string>...".into()]);
-    };
-    let mut command = std::process::Command::new(head);
-    command.stdout(std::process::Stdio::piped());
-    command.args(rest);
-    let process = command
-        .spawn()
-        .map_err(|err| vec!["unix: spawn:".into(), err.to_string()])?;
-    let output = process
-        .wait_with_output()
-        .map_err(|err| vec!["unix: wait:".into(), err.to_string()])?;
-    let string =
-        String::from_utf8(output.stdout).map_err(|_| vec!["unix: output is not UTF-8".into()])?;
+    let Some(program) = Program::from_values(env, args) else {
+        return Err(Error::fail("unix <string>..."));
+    };
+    if program.args.is_empty() {
+        return Err(Error::fail("unix cmd <string>..."));
+    }
+    env.pipeline(&[Stage::Program(program)], "unix")
+}
+
+pub(crate) fn run(env: &mut Env, args: &[Gc<Value>]) -> Result {
+    let Some(program) = Program::from_values(env, args) else {
+        return Err(Error::fail("run <string>..."));
+    };
+    env.run(&program, "run")
+}
+
+pub(crate) fn redirect(env: &mut Env, args: &[Gc<Value>]) -> Result {
+    const USAGE: &str = "redirect <op: < | > | >> | 2> | 2>> | 2>&1> <file>?";
+    let (op, file) = match args {
+        [op] => (op, None),
+        [op, file] => (op, Some(file)),
+        _ => return Err(Error::fail(USAGE)),
+    };
+    let Value::String(op) = env.gc.get(*op) else {
+        return Err(Error::fail(USAGE));
+    };
+    if file.is_none() && matches!(op.as_str(), "<" | ">" | ">>" | "2>" | "2>>") {
+        return Err(Error::fail(format!("`{op}` needs a file")));
+    }
+    let file = match file.map(|&file| env.gc.get(file)) {
+        None => None,
+        Some(Value::String(file)) => Some(file.clone()),
+        Some(Value::Number(n)) => Some(n.to_string()),
+        Some(_) => return Err(Error::fail(USAGE)),
+    };
+    let Some(redirect) = Redirect::new(op, file) else {
+        return Err(Error::fail(USAGE));
+    };
+    Ok(env.gc.rooted(Value::Redirect(redirect)))
+}
+
+pub(crate) fn errexit(env: &mut Env, args: &[Gc<Value>]) -> Result {
+    let [arg] = args else {
+        return Err(Error::fail("errexit <true | false>"));
+    };
+    env.errexit = match env.gc.get(*arg) {
+        Value::String(s) if s == "true" => true,
+        Value::String(s) if s == "false" => false,
+        _ => return Err(Error::fail("errexit <true | false>")),
+    };
+    Ok(env.gc.rooted(Value::String("ok".into())))
+}
+
+pub(crate) fn encode(env: &mut Env, args: &[Gc<Value>]) -> Result {
+    let [arg] = args else {
+        return Err(Error::fail("encode <string>"));
+    };
+    let Value::String(s) = env.gc.get(*arg) else {
+        return Err(Error::fail("encode <string>"));
+    };
+    let bytes = s.clone().into_bytes();
+    Ok(env.gc.rooted(Value::Bytes(bytes)))
+}
+
+pub(crate) fn decode(env: &mut Env, args: &[Gc<Value>]) -> Result {
+    let (bytes, lossy) = match args {
+        [bytes] => (bytes, false),
+        [bytes, lossy] if matches!(env.gc.get(*lossy), Value::String(s) if s == "lossy") => {
+            (bytes, true)
+        }
+        _ => return Err(Error::fail("decode <bytes> <lossy>?")),
+    };
+    let bytes = match env.gc.get(*bytes) {
+        Value::Bytes(bytes) => bytes,
+        // Output that's UTF-8 is a string already.
+        Value::String(_) => return Ok(env.gc.root(*bytes)),
+        _ => return Err(Error::fail("decode <bytes> <lossy>?")),
+    };
+    let string = if lossy {
+        String::from_utf8_lossy(bytes).into_owned()
+    } else {
+        String::from_utf8(bytes.clone()).map_err(|_| Error::fail("decode: bytes are not UTF-8"))?
+    };
     Ok(env.gc.rooted(Value::String(string)))
 }
 
+pub(crate) fn pipe(env: &mut Env, args: &[Gc<Value>]) -> Result {
+    let mut stages = Vec::new();
+    for &arg in args {
+        let stage = match env.gc.get(arg) {
+            Value::String(program) => Stage::Program(Program {
+                args: vec![program.into()],
+                redirects: Vec::new(),
+            }),
+            Value::List(list) => {
+                let Some(program) = Program::from_values(env, list) else {
+                    return Err(Error::fail("pipe <stage: list of strings>..."));
+                };
+                Stage::Program(program)
+            }
+            Value::Builtin(_) | Value::Callable(_) => Stage::Function(arg),
+            _ => return Err(Error::fail("pipe <stage: program | list | fn>...")),
+        };
+        stages.push(stage);
+    }
+    env.pipeline(&stages, "pipe")
+}
+
 pub(crate) fn lines(env: &mut Env, args: &[Gc<Value>]) -> Result {
     let [arg] = args else {
-        return Err(vec!["lines <string>".into()]);
+        return Err(Error::fail("lines <string>"));
     };
     let Value::String(s) = env.gc.get(*arg) else {
-        return Err(vec!["lines <string>".into()]);
+        return Err(Error::fail("lines <string>"));
     };
     let owned = s.to_owned();
-    let mut map = BTreeMap::new();
-    for (index, segment) in owned.split_terminator('\n').enumerate() {
-        let key = index.to_string();
-        let value = env.gc.rooted(Value::String(segment.to_owned()));
-        map.insert(key, value);
+    let mut list = Vec::new();
+    for segment in owned.split_terminator('\n') {
+        list.push(env.gc.rooted(Value::String(segment.to_owned())));
     }
-    let map_value = env.gc.rooted(Value::Map(map));
-    let Value::Map(map) = env.gc.get(map_value) else {
-        unreachable!()
-    };
-    let entries = map.values().copied().collect::<Vec<_>>();
-    for entry in entries {
+    let list_value = env.gc.rooted(Value::List(list.clone()));
+    for entry in list {
         env.gc.unroot(entry);
     }
-    Ok(map_value)
+    Ok(list_value)
 }
 
 // General continuations seem to be unimplementable as of now, not enough reification.
 pub(crate) fn catch(env: &mut Env, args: &[Expr]) -> Result {
     let [ref body] = args[..] else {
-        return Err(vec!["catch <body>".into()]);
+        return Err(Error::fail("catch <body>"));
     };
 
-    let value = env.eval_expr(body)?;
-
-    if let Value::Exception(throw) = env.gc.get(value) {
-        let throw = *throw;
-        env.gc.root(throw);
-        env.gc.unroot(value);
-        Ok(throw)
-    } else {
-        Ok(value)
+    match env.eval_expr(body) {
+        // The thrown value is already rooted.
+        Err(Error::Throw(value)) => Ok(value),
+        result => result,
     }
 }
 
 pub(crate) fn throw(env: &mut Env, args: &[Gc<Value>]) -> Result {
     let [throw] = args[..] else {
-        return Err(vec!["throw <value>".into()]);
+        return Err(Error::fail("throw <value>"));
     };
-    Ok(env.gc.rooted(Value::Exception(throw)))
+    Err(Error::Throw(env.gc.root(throw)))
 }
 
 pub(crate) fn concat(env: &mut Env, args: &[Gc<Value>]) -> Result {
     let mut result = String::new();
     for &arg in args {
-        let Value::String(s) = env.gc.get(arg) else {
-            return Err(vec!["..: <value: string>...".into()]);
-        };
-        result.push_str(s);
+        match env.gc.get(arg) {
+            Value::String(s) => result.push_str(s),
+            Value::Number(n) => result.push_str(&n.to_string()),
+            _ => return Err(Error::fail("..: <value: string>...")),
+        }
     }
     Ok(env.gc.rooted(Value::String(result)))
 }
 
 pub(crate) fn cond(env: &mut Env, args: &[Expr]) -> Result {
     let [cond, then, otherwise] = args else {
-        return Err(vec!["if <cond> <then> <else>".into()]);
+        return Err(Error::fail("if <cond> <then> <else>"));
     };
     let cond_value = env.eval_expr(cond)?;
     let Value::String(cond) = env.gc.get(cond_value) else {
-        return Err(vec!["if: <cond: string>".into()]);
+        return Err(Error::fail("if: <cond: string>"));
     };
     let result = env.eval_expr(if cond == "true" { then } else { otherwise });
     env.gc.unroot(cond_value);
     result
 }
 
+// Strings are equal if they're the same, numbers if they have the same value,
+// and lists if their values are equal.
+// Other values are never equal.
+fn equal_values(env: &Env, l: Gc<Value>, r: Gc<Value>) -> bool {
+    equal_values_in(env, l, r, &mut HashSet::new())
+}
+
+// `compared` has the pairs of lists being compared already. They're taken to be equal
+// when met again, so that lists that contain themselves are compared without looping forever.
+fn equal_values_in(
+    env: &Env,
+    l: Gc<Value>,
+    r: Gc<Value>,
+    compared: &mut HashSet<(Gc<Value>, Gc<Value>)>,
+) -> bool {
+    if l == r {
+        return true;
+    }
+    match (env.gc.get(l), env.gc.get(r)) {
+        (Value::String(l), Value::String(r)) => l == r,
+        (Value::Bytes(l), Value::Bytes(r)) => l == r,
+        (Value::Number(l), Value::Number(r)) => l.compare(r) == Some(Ordering::Equal),
+        // A string is equal to a number if it reads as an equal number.
+        (Value::Number(n), Value::String(s)) | (Value::String(s), Value::Number(n)) => {
+            Number::parse(s).is_some_and(|s| s.compare(n) == Some(Ordering::Equal))
+        }
+        (Value::List(ls), Value::List(rs)) => {
+            if !compared.insert((l, r)) {
+                return true;
+            }
+            ls.len() == rs.len()
+                && ls
+                    .iter()
+                    .zip(rs)
+                    .all(|(&l, &r)| equal_values_in(env, l, r, compared))
+        }
+        _ => false,
+    }
+}
+
 pub(crate) fn equal(env: &mut Env, args: &[Gc<Value>]) -> Result {
     let [l, r] = args[..] else {
-        return Err(vec!["= <a> <b>".into()]);
-    };
-    let (Value::String(l), Value::String(r)) = (env.gc.get(l), env.gc.get(r)) else {
-        return Ok(env.gc.rooted(Value::String("false".into())));
+        return Err(Error::fail("= <a> <b>"));
     };
-    if l == r {
+    if equal_values(env, l, r) {
         Ok(env.gc.rooted(Value::String("true".into())))
     } else {
         Ok(env.gc.rooted(Value::String("false".into())))
@@ -236,12 +397,9 @@ pub(crate) fn equal(env: &mut Env, args: &[Gc<Value>]) -> Result {
 
 pub(crate) fn not_equal(env: &mut Env, args: &[Gc<Value>]) -> Result {
     let [l, r] = args[..] else {
-        return Err(vec!["= <a> <b>".into()]);
-    };
-    let (Value::String(l), Value::String(r)) = (env.gc.get(l), env.gc.get(r)) else {
-        return Ok(env.gc.rooted(Value::String("true".into())));
+        return Err(Error::fail("!= <a> <b>"));
     };
-    if l == r {
+    if equal_values(env, l, r) {
         Ok(env.gc.rooted(Value::String("false".into())))
     } else {
         Ok(env.gc.rooted(Value::String("true".into())))
@@ -250,20 +408,20 @@ pub(crate) fn not_equal(env: &mut Env, args: &[Gc<Value>]) -> Result {
 
 pub(crate) fn set(env: &mut Env, args: &[Gc<Value>]) -> Result {
     let [name, value] = args[..] else {
-        return Err(vec!["set <name> <value>".into()]);
+        return Err(Error::fail("set <name> <value>"));
     };
     let Value::String(name) = env.gc.get(name) else {
-        return Err(vec!["set <name: string> <value>".into()]);
+        return Err(Error::fail("set <name: string> <value>"));
     };
     if !env.update(&name.to_owned(), value) {
-        return Err(vec!["set: var not found".into()]);
+        return Err(Error::fail("set: var not found"));
     }
     Ok(env.gc.rooted(Value::String("ok".into())))
 }
 
 pub(crate) fn val(env: &mut Env, tail_values: &[Gc<Value>]) -> Result {
     let [value] = tail_values[..] else {
-        return Err(vec!["val <value>".into()]);
+        return Err(Error::fail("val <value>"));
     };
     env.gc.root(value);
     Ok(value)
@@ -289,11 +447,11 @@ pub(crate) fn print(env: &mut Env, tail_values: &[Gc<Value>]) -> Result {
 pub(crate) fn var(env: &mut Env, tail_values: &[Gc<Value>]) -> Result {
     for chunk in tail_values.chunks(2) {
         let [name, value] = chunk else {
-            return Err(vec!["var { <name> <value> }".into()]);
+            return Err(Error::fail("var { <name> <value> }"));
         };
 
         let Value::String(name) = env.gc.get(*name) else {
-            return Err(vec!["set <name: string> <value>".into()]);
+            return Err(Error::fail("set <name: string> <value>"));
         };
 
         let name = name.into();
@@ -308,14 +466,14 @@ pub(crate) fn var(env: &mut Env, tail_values: &[Gc<Value>]) -> Result {
 
 pub(crate) fn get(env: &mut Env, tail_values: &[Gc<Value>]) -> Result {
     let [name] = tail_values[..] else {
-        return Err(vec!["get <name>".into()]);
+        return Err(Error::fail("get <name>"));
     };
     let Value::String(name) = env.gc.get(name) else {
-        return Err(vec!["get <name: string>".into()]);
+        return Err(Error::fail("get <name: string>"));
     };
     let name = name.to_owned();
     let Some(value) = env.lookup(&name) else {
-        return Err(vec!["get: var not found".into()]);
+        return Err(Error::fail("get: var not found"));
     };
     env.gc.root(value);
     Ok(value)
@@ -323,87 +481,214 @@ pub(crate) fn get(env: &mut Env, tail_values: &[Gc<Value>]) -> Result {
 
 pub(crate) fn del(env: &mut Env, tail_values: &[Gc<Value>]) -> Result {
     let [name] = tail_values[..] else {
-        return Err(vec!["del <name>".into()]);
+        return Err(Error::fail("del <name>"));
     };
     let Value::String(name) = env.gc.get(name) else {
-        return Err(vec!["del <name: string>".into()]);
+        return Err(Error::fail("del <name: string>"));
     };
     let name = name.to_owned();
     if !env.forget(&name) {
-        return Err(vec!["del: var not found".into()]);
+        return Err(Error::fail("del: var not found"));
     };
     Ok(env.gc.rooted(Value::String("ok".into())))
 }
 
+// Reads a number, or a string that looks like one.
+fn number(env: &Env, value: Gc<Value>, name: &str) -> Result<Number> {
+    match env.gc.get(value) {
+        Value::Number(n) => Ok(n.clone()),
+        Value::String(s) => {
+            Number::parse(s).ok_or_else(|| Error::fail(format!("{name}: `{s}` isn't a number")))
+        }
+        _ => Err(Error::fail(format!("{name}: expected a number"))),
+    }
+}
+
+fn numbers(env: &Env, values: &[Gc<Value>], name: &str) -> Result<Vec<Number>> {
+    values.iter().map(|&v| number(env, v, name)).collect()
+}
+
+// Big integers multiply and print in time quadratic in their length,
+// so a power of this many bits takes a fraction of a second, but 100 times more would take minutes.
+const MAX_POWER_BITS: u64 = 1 << 16;
+
 pub(crate) fn inc(env: &mut Env, tail_values: &[Gc<Value>]) -> Result {
     let [value] = tail_values[..] else {
-        return Err(vec!["inc <number>".into()]);
-    };
-    let Value::String(value) = env.gc.get(value) else {
-        return Err(vec!["inc <number: string>".into()]);
+        return Err(Error::fail("inc <number>"));
     };
-    let Ok(n) = value.parse::<isize>() else {
-        return Err(vec!["inc: parse failed".into()]);
-    };
-    let str = format!("{}", n + 1);
-    Ok(env.gc.rooted(Value::String(str)))
+    let n = number(env, value, "inc")?;
+    let n = n.op(&Number::Int(1), i64::checked_add, BigInt::add, |a, b| a + b);
+    Ok(env.gc.rooted(Value::Number(n)))
 }
 
 pub(crate) fn add(env: &mut Env, tail_values: &[Gc<Value>]) -> Result {
-    let mut sum = 0;
-    for &value in tail_values {
-        let value = env.gc.get(value);
-        let Value::String(value) = value else {
-            return Err(vec!["+ <number: string>...".into()]);
-        };
-        let Ok(number) = value.parse::<isize>() else {
-            return Err(vec!["+: parse failed".into()]);
-        };
-        sum += number;
+    let mut sum = Number::Int(0);
+    for n in numbers(env, tail_values, "+")? {
+        sum = sum.op(&n, i64::checked_add, BigInt::add, |a, b| a + b);
     }
-    let string = format!("{sum}");
-    Ok(env.gc.rooted(Value::String(string)))
+    Ok(env.gc.rooted(Value::Number(sum)))
 }
 
 pub(crate) fn mul(env: &mut Env, tail_values: &[Gc<Value>]) -> Result {
-    let mut product = 1;
-    for &value in tail_values {
-        let value = env.gc.get(value);
-        let Value::String(value) = value else {
-            return Err(vec!["* <number: string>...".into()]);
-        };
-        let Ok(number) = value.parse::<isize>() else {
-            return Err(vec!["*: parse failed".into()]);
-        };
-        product *= number;
+    let mut product = Number::Int(1);
+    for n in numbers(env, tail_values, "*")? {
+        product = product.op(&n, i64::checked_mul, BigInt::mul, |a, b| a * b);
     }
-    let string = format!("{product}");
-    Ok(env.gc.rooted(Value::String(string)))
+    Ok(env.gc.rooted(Value::Number(product)))
+}
+
+pub(crate) fn sub(env: &mut Env, tail_values: &[Gc<Value>]) -> Result {
+    let numbers = numbers(env, tail_values, "-")?;
+    let (first, rest) = match &numbers[..] {
+        [] => return Err(Error::fail("- <number> <number>...")),
+        // With one argument, it's negated.
+        [n] => (Number::Int(0), std::slice::from_ref(n)),
+        [first, rest @ ..] => (first.clone(), rest),
+    };
+    let mut difference = first;
+    for n in rest {
+        difference = difference.op(n, i64::checked_sub, BigInt::sub, |a, b| a - b);
+    }
+    Ok(env.gc.rooted(Value::Number(difference)))
+}
+
+pub(crate) fn div(env: &mut Env, args: &[Gc<Value>]) -> Result {
+    let &[a, b] = args else {
+        return Err(Error::fail("/ <a: number> <b: number>"));
+    };
+    let (a, b) = (number(env, a, "/")?, number(env, b, "/")?);
+    if a.is_integer() && b.is_integer() && b.is_zero() {
+        return Err(Error::fail("/: division by zero"));
+    }
+    let quotient = |a: &BigInt, b: &BigInt| a.div_rem(b).unwrap().0;
+    let n = a.op(&b, i64::checked_div, quotient, |a, b| a / b);
+    Ok(env.gc.rooted(Value::Number(n)))
+}
+
+pub(crate) fn rem(env: &mut Env, args: &[Gc<Value>]) -> Result {
+    let &[a, b] = args else {
+        return Err(Error::fail("% <a: number> <b: number>"));
+    };
+    let (a, b) = (number(env, a, "%")?, number(env, b, "%")?);
+    if a.is_integer() && b.is_integer() && b.is_zero() {
+        return Err(Error::fail("%: division by zero"));
+    }
+    let remainder = |a: &BigInt, b: &BigInt| a.div_rem(b).unwrap().1;
+    let n = a.op(&b, i64::checked_rem, remainder, |a, b| a % b);
+    Ok(env.gc.rooted(Value::Number(n)))
+}
+
+pub(crate) fn pow(env: &mut Env, args: &[Gc<Value>]) -> Result {
+    let &[base, exponent] = args else {
+        return Err(Error::fail("** <base: number> <exponent: number>"));
+    };
+    let (base, exponent) = (number(env, base, "**")?, number(env, exponent, "**")?);
+    let n = base
+        .pow(&exponent, MAX_POWER_BITS)
+        .ok_or_else(|| Error::fail("**: the result is too big"))?;
+    Ok(env.gc.rooted(Value::Number(n)))
+}
+
+fn compare(env: &mut Env, args: &[Gc<Value>], name: &str, holds: fn(Ordering) -> bool) -> Result {
+    let &[a, b] = args else {
+        return Err(Error::fail(format!("{name} <a: number> <b: number>")));
+    };
+    let (a, b) = (number(env, a, name)?, number(env, b, name)?);
+    // NaN compares false to everything.
+    let result = a.compare(&b).is_some_and(holds);
+    Ok(env
+        .gc
+        .rooted(Value::String(if result { "true" } else { "false" }.into())))
+}
+
+pub(crate) fn less(env: &mut Env, args: &[Gc<Value>]) -> Result {
+    compare(env, args, "<", Ordering::is_lt)
+}
+
+pub(crate) fn more(env: &mut Env, args: &[Gc<Value>]) -> Result {
+    compare(env, args, ">", Ordering::is_gt)
+}
+
+pub(crate) fn less_or_equal(env: &mut Env, args: &[Gc<Value>]) -> Result {
+    compare(env, args, "<=", Ordering::is_le)
+}
+
+pub(crate) fn more_or_equal(env: &mut Env, args: &[Gc<Value>]) -> Result {
+    compare(env, args, ">=", Ordering::is_ge)
+}
+
+pub(crate) fn abs(env: &mut Env, args: &[Gc<Value>]) -> Result {
+    let &[n] = args else {
+        return Err(Error::fail("abs <number>"));
+    };
+    let n = number(env, n, "abs")?.abs();
+    Ok(env.gc.rooted(Value::Number(n)))
+}
+
+fn extremum(env: &mut Env, args: &[Gc<Value>], name: &str, wanted: Ordering) -> Result {
+    let numbers = numbers(env, args, name)?;
+    let [first, rest @ ..] = &numbers[..] else {
+        return Err(Error::fail(format!("{name} <number> <number>...")));
+    };
+    let mut extremum = first;
+    for n in rest {
+        if n.compare(extremum) == Some(wanted) {
+            extremum = n;
+        }
+    }
+    Ok(env.gc.rooted(Value::Number(extremum.clone())))
+}
+
+pub(crate) fn min(env: &mut Env, args: &[Gc<Value>]) -> Result {
+    extremum(env, args, "min", Ordering::Less)
+}
+
+pub(crate) fn max(env: &mut Env, args: &[Gc<Value>]) -> Result {
+    extremum(env, args, "max", Ordering::Greater)
+}
+
+fn to_integer(env: &mut Env, args: &[Gc<Value>], name: &str, round: fn(f64) -> f64) -> Result {
+    let &[n] = args else {
+        return Err(Error::fail(format!("{name} <number>")));
+    };
+    let n = number(env, n, name)?;
+    let Some(i) = n.to_integer(round) else {
+        return Err(Error::fail(format!("{name}: {n} isn't a finite number")));
+    };
+    Ok(env.gc.rooted(Value::Number(i)))
+}
+
+pub(crate) fn round(env: &mut Env, args: &[Gc<Value>]) -> Result {
+    to_integer(env, args, "round", f64::round)
+}
+
+pub(crate) fn floor(env: &mut Env, args: &[Gc<Value>]) -> Result {
+    to_integer(env, args, "floor", f64::floor)
+}
+
+pub(crate) fn ceil(env: &mut Env, args: &[Gc<Value>]) -> Result {
+    to_integer(env, args, "ceil", f64::ceil)
 }
 
 pub(crate) fn map(env: &mut Env, mut tail: &[Gc<Value>]) -> Result {
-    let mut map = BTreeMap::new();
+    let mut map = OrderedMap::new();
     while let [k, v, rest @ ..] = tail {
-        let Value::String(k) = env.gc.get(*k) else {
-            return Err(vec!["map: (<k: string> <value>)...".into()]);
-        };
-        map.insert(k.clone(), *v);
+        map.insert(env.key(*k), *v);
         tail = rest;
     }
     Ok(env.gc.rooted(Value::Map(map)))
 }
 
+pub(crate) fn list(env: &mut Env, tail: &[Gc<Value>]) -> Result {
+    Ok(env.gc.rooted(Value::List(tail.to_vec())))
+}
+
 pub(crate) fn repeat(env: &mut Env, args: &[Expr]) -> Result {
     let [ref body] = args[..] else {
-        return Err(vec!["repeat <body>".into()]);
+        return Err(Error::fail("repeat <body>"));
     };
     loop {
         let value = env.eval_expr(body)?;
-        // It's annoying that we have to handle this here manually.
-        if let Value::Exception(_) = env.gc.get(value) {
-            return Ok(value);
-        } else {
-            env.gc.unroot(value);
-        }
+        env.gc.unroot(value);
     }
 }
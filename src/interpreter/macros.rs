@@ -0,0 +1,172 @@
+use std::{collections::HashMap, rc::Rc};
+
+use crate::{
+    interpreter::{Error, Result},
+    syntax::{Command, Commands, Expr},
+};
+
+// Deeper expansions are most likely a macro that expands to itself.
+const MAX_DEPTH: usize = 64;
+
+/// Macros defined with `macro <name> <param>... (<template>)`, by name.
+/// They are expanded before evaluation, so they're visible in the code after their definition.
+#[derive(Default, Clone)]
+pub struct Macros(HashMap<String, Macro>);
+
+#[derive(Clone)]
+struct Macro {
+    params: Vec<String>,
+    template: Command,
+}
+
+impl Macros {
+    /// Defines the macros in `commands` and expands their uses, in order.
+    /// Definitions are left out, and so are the arguments of `macroexpand`.
+    pub fn expand(&mut self, commands: &Commands) -> Result<Commands> {
+        self.expand_commands(commands, 0)
+    }
+
+    fn expand_commands(&mut self, commands: &Commands, depth: usize) -> Result<Commands> {
+        let mut result = Vec::new();
+        for command in &commands.0 {
+            if let Some(command) = self.expand_command(command, depth)? {
+                result.push(command);
+            }
+        }
+        Ok(Commands(result))
+    }
+
+    fn expand_command(&mut self, command: &Command, depth: usize) -> Result<Option<Command>> {
+        let name = match command.exprs.first() {
+            Some(Expr::String(name, _)) => name.as_str(),
+            _ => "",
+        };
+        match name {
+            "macro" => {
+                self.define(command).map_err(|e| e.at(command.span))?;
+                return Ok(None);
+            }
+            "macroexpand" => return Ok(Some(command.clone())),
+            _ => (),
+        }
+        let Some(definition) = self.0.get(name) else {
+            let mut exprs = Vec::new();
+            for expr in &command.exprs {
+                exprs.push(self.expand_expr(expr, depth)?);
+            }
+            return Ok(Some(Command {
+                exprs,
+                span: command.span,
+                redirections: command.redirections.clone(),
+            }));
+        };
+        let args = &command.exprs[1..];
+        if args.len() != definition.params.len() {
+            let s = if definition.params.len() == 1 {
+                ""
+            } else {
+                "s"
+            };
+            let error = format!(
+                "macro `{name}` takes {} argument{s}, got {}",
+                definition.params.len(),
+                args.len()
+            );
+            return Err(Error::fail(error).at(command.span));
+        }
+        if depth == MAX_DEPTH {
+            let error = format!("macro `{name}` expands too deeply");
+            return Err(Error::fail(error).at(command.span));
+        }
+        let params: HashMap<_, _> = definition
+            .params
+            .iter()
+            .map(String::as_str)
+            .zip(args)
+            .collect();
+        let mut expansion = substitute_command(&definition.template, &params);
+        expansion.span = command.span;
+        self.expand_command(&expansion, depth + 1)
+    }
+
+    fn expand_expr(&mut self, expr: &Expr, depth: usize) -> Result<Expr> {
+        Ok(match expr {
+            Expr::String(..) => expr.clone(),
+            Expr::Closure(commands, span) => {
+                Expr::Closure(Rc::new(self.expand_commands(commands, depth)?), *span)
+            }
+            Expr::Block(commands, span) => {
+                Expr::Block(Rc::new(self.expand_commands(commands, depth)?), *span)
+            }
+        })
+    }
+
+    fn define(&mut self, command: &Command) -> Result<()> {
+        let [
+            _,
+            Expr::String(name, _),
+            params @ ..,
+            Expr::Closure(template, _),
+        ] = &command.exprs[..]
+        else {
+            return Err(Error::fail("macro <name> <param>... (<template>)"));
+        };
+        let mut names = Vec::new();
+        for param in params {
+            let Expr::String(param, _) = param else {
+                return Err(Error::fail("macro <name> <param: string>... (<template>)"));
+            };
+            names.push(param.clone());
+        }
+        let [template] = &template.0[..] else {
+            return Err(Error::fail("macro: the template must be one command"));
+        };
+        self.0.insert(
+            name.clone(),
+            Macro {
+                params: names,
+                template: template.clone(),
+            },
+        );
+        Ok(())
+    }
+}
+
+// Replaces `$param` with the argument for it, even in nested closures.
+fn substitute_command(command: &Command, params: &HashMap<&str, &Expr>) -> Command {
+    Command {
+        exprs: command
+            .exprs
+            .iter()
+            .map(|expr| substitute_expr(expr, params))
+            .collect(),
+        span: command.span,
+        redirections: command.redirections.clone(),
+    }
+}
+
+fn substitute_expr(expr: &Expr, params: &HashMap<&str, &Expr>) -> Expr {
+    let substitute_commands = |commands: &Commands| {
+        let commands = commands
+            .0
+            .iter()
+            .map(|command| substitute_command(command, params))
+            .collect();
+        Rc::new(Commands(commands))
+    };
+    match expr {
+        Expr::String(..) => expr.clone(),
+        Expr::Block(commands, span) => {
+            // `$param` is `$(get param)`.
+            if let [command] = &commands.0[..]
+                && let [Expr::String(get, _), Expr::String(name, _)] = &command.exprs[..]
+                && get == "get"
+                && let Some(&arg) = params.get(name.as_str())
+            {
+                return arg.clone();
+            }
+            Expr::Block(substitute_commands(commands), *span)
+        }
+        Expr::Closure(commands, span) => Expr::Closure(substitute_commands(commands), *span),
+    }
+}
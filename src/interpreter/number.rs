@@ -0,0 +1,159 @@
+use std::{cmp::Ordering, fmt};
+
+use super::bigint::BigInt;
+
+/// A number made by arithmetic. Strings that look like numbers work as numbers too.
+#[derive(Debug, Clone)]
+pub enum Number {
+    Int(i64),
+    /// An integer that doesn't fit in an i64. Integers that do are never big.
+    Big(BigInt),
+    Float(f64),
+}
+
+impl Number {
+    /// Reads an integer of any length like `-12`, or a float like `1.5` or `2e10`.
+    pub fn parse(s: &str) -> Option<Number> {
+        if let Ok(i) = s.parse() {
+            return Some(Number::Int(i));
+        }
+        if let Some(big) = BigInt::parse(s) {
+            return Some(Number::Big(big));
+        }
+        // Rust also reads words like `inf` and `nan`, which are better left as strings.
+        let digits = s.trim_start_matches(['-', '+']);
+        if !digits.starts_with(|c: char| c.is_ascii_digit()) {
+            return None;
+        }
+        s.parse().ok().map(Number::Float)
+    }
+
+    // Keeps integers that fit in an i64 small.
+    fn from_big(big: BigInt) -> Number {
+        match big.to_i64() {
+            Some(i) => Number::Int(i),
+            None => Number::Big(big),
+        }
+    }
+
+    fn to_big(&self) -> Option<BigInt> {
+        match self {
+            Number::Int(i) => Some(BigInt::from_i64(*i)),
+            Number::Big(big) => Some(big.clone()),
+            Number::Float(_) => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> f64 {
+        match self {
+            Number::Int(i) => *i as f64,
+            Number::Big(big) => big.to_f64(),
+            Number::Float(f) => *f,
+        }
+    }
+
+    /// Applies an operation on integers, falling back to big integers when it overflows,
+    /// which `int` tells by returning None. If either number is a float, so is the result.
+    pub fn op(
+        &self,
+        other: &Number,
+        int: impl Fn(i64, i64) -> Option<i64>,
+        big: impl Fn(&BigInt, &BigInt) -> BigInt,
+        float: impl Fn(f64, f64) -> f64,
+    ) -> Number {
+        if let (Number::Int(a), Number::Int(b)) = (self, other)
+            && let Some(i) = int(*a, *b)
+        {
+            return Number::Int(i);
+        }
+        match (self.to_big(), other.to_big()) {
+            (Some(a), Some(b)) => Number::from_big(big(&a, &b)),
+            _ => Number::Float(float(self.as_f64(), other.as_f64())),
+        }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        match self {
+            Number::Int(i) => *i == 0,
+            // Zero is small.
+            Number::Big(_) => false,
+            Number::Float(f) => *f == 0.0,
+        }
+    }
+
+    pub fn is_integer(&self) -> bool {
+        !matches!(self, Number::Float(_))
+    }
+
+    /// Raises a number to a power. An integer to a power that's an integer and not negative
+    /// is exact, failing if the result has more than `max_bits` bits; anything else is a float.
+    pub fn pow(&self, exponent: &Number, max_bits: u64) -> Option<Number> {
+        let (Some(base), Some(exponent)) = (self.to_big(), exponent.to_big()) else {
+            return Some(Number::Float(self.as_f64().powf(exponent.as_f64())));
+        };
+        if exponent < BigInt::from_i64(0) {
+            return Some(Number::Float(self.as_f64().powf(exponent.to_f64())));
+        }
+        // These have small powers however big the exponent is.
+        match self {
+            Number::Int(0) if exponent.is_zero() => return Some(Number::Int(1)),
+            Number::Int(b @ (0 | 1)) => return Some(Number::Int(*b)),
+            Number::Int(-1) => {
+                let (_, odd) = exponent.div_rem(&BigInt::from_i64(2))?;
+                return Some(Number::Int(if odd.is_zero() { 1 } else { -1 }));
+            }
+            _ => (),
+        }
+        let exponent = u32::try_from(exponent.to_i64()?).ok()?;
+        if let Number::Int(i) = self
+            && let Some(power) = i.checked_pow(exponent)
+        {
+            return Some(Number::Int(power));
+        }
+        if base.bits().saturating_mul(exponent as u64) > max_bits {
+            return None;
+        }
+        Some(Number::from_big(base.pow(exponent)))
+    }
+
+    pub fn abs(&self) -> Number {
+        match self {
+            Number::Int(i) => match i.checked_abs() {
+                Some(i) => Number::Int(i),
+                None => Number::Big(BigInt::from_i64(*i).abs()),
+            },
+            Number::Big(big) => Number::Big(big.abs()),
+            Number::Float(f) => Number::Float(f.abs()),
+        }
+    }
+
+    /// Integers, big or not, and floats are compared by value. NaN isn't comparable.
+    pub fn compare(&self, other: &Number) -> Option<Ordering> {
+        match (self, other) {
+            (Number::Int(a), Number::Int(b)) => Some(a.cmp(b)),
+            (a, b) => match (a.to_big(), b.to_big()) {
+                (Some(a), Some(b)) => Some(a.cmp(&b)),
+                _ => a.as_f64().partial_cmp(&b.as_f64()),
+            },
+        }
+    }
+
+    /// Rounds a float to an integer with `round`, unless it's infinite or NaN.
+    pub fn to_integer(&self, round: impl Fn(f64) -> f64) -> Option<Number> {
+        match self {
+            Number::Float(f) => BigInt::from_f64(round(*f)).map(Number::from_big),
+            integer => Some(integer.clone()),
+        }
+    }
+}
+
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Number::Int(i) => write!(f, "{i}"),
+            Number::Big(big) => write!(f, "{big}"),
+            // Debug formatting keeps the point in `2.0`, so that it reads back as a float.
+            Number::Float(x) => write!(f, "{x:?}"),
+        }
+    }
+}
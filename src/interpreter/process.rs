@@ -0,0 +1,614 @@
+use std::{
+    borrow::Cow,
+    ffi::{OsStr, OsString},
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, PipeReader, Read, Write},
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        fs::PermissionsExt,
+        process::{CommandExt, ExitStatusExt},
+    },
+    path::Path,
+    process::{Child, ChildStdin, Command, ExitStatus, Stdio},
+    rc::Rc,
+    thread::{self, JoinHandle},
+};
+
+use gc::Gc;
+
+use crate::{
+    interpreter::{Env, Error, Key, Number, Result, Value},
+    ordered_map::OrderedMap,
+    syntax::{self, Expr},
+};
+
+/// Where a program's input or output goes instead, made by `< file`, `> file` and so on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Redirect {
+    /// `< file`
+    Input(String),
+    /// `> file`, or `>> file` if it appends.
+    Output { file: String, append: bool },
+    /// `2> file`, or `2>> file` if it appends.
+    Error { file: String, append: bool },
+    /// `2>&1`
+    ErrorToOutput,
+}
+
+impl Redirect {
+    /// Makes a redirection from its operator and its file, which all but `2>&1` need.
+    pub fn new(op: &str, file: Option<String>) -> Option<Redirect> {
+        match (op, file) {
+            ("2>&1", None) => Some(Redirect::ErrorToOutput),
+            ("<", Some(file)) => Some(Redirect::Input(file)),
+            (">" | ">>", Some(file)) => Some(Redirect::Output {
+                file,
+                append: op == ">>",
+            }),
+            ("2>" | "2>>", Some(file)) => Some(Redirect::Error {
+                file,
+                append: op == "2>>",
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Redirect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let append = |append| if append { ">>" } else { ">" };
+        match self {
+            Redirect::Input(file) => write!(f, "< {file}"),
+            Redirect::Output { file, append: a } => write!(f, "{} {file}", append(*a)),
+            Redirect::Error { file, append: a } => write!(f, "2{} {file}", append(*a)),
+            Redirect::ErrorToOutput => write!(f, "2>&1"),
+        }
+    }
+}
+
+/// A program to run, with its arguments and where its input and output go.
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    /// The program's name and its arguments.
+    pub args: Vec<OsString>,
+    /// Applied in order, so that `> file 2>&1` sends both outputs to the file.
+    pub redirects: Vec<Redirect>,
+}
+
+impl Program {
+    /// Sorts values into arguments, which are strings, numbers or bytes, and redirections.
+    /// None if there's any other value.
+    pub fn from_values(env: &Env, values: &[Gc<Value>]) -> Option<Program> {
+        let mut program = Program::default();
+        for &value in values {
+            match env.gc.get(value) {
+                Value::String(s) => program.args.push(s.into()),
+                Value::Number(n) => program.args.push(n.to_string().into()),
+                Value::Bytes(b) => program.args.push(OsString::from_vec(b.clone())),
+                Value::Redirect(redirect) => program.redirects.push(redirect.clone()),
+                _ => return None,
+            }
+        }
+        Some(program)
+    }
+}
+
+/// A part of a pipeline.
+pub enum Stage {
+    Program(Program),
+    /// A function called with the output of the stage before as a string, or as bytes
+    /// if it isn't UTF-8, returning the input of the stage after.
+    Function(Gc<Value>),
+}
+
+// Where the next stage reads from.
+enum Input {
+    Inherit,
+    // The stage before wrote its output to a file.
+    Null,
+    Pipe(PipeReader),
+    Bytes(Vec<u8>),
+}
+
+enum Output {
+    Bytes(Vec<u8>),
+    // What a function at the end returned, rooted.
+    Value(Gc<Value>),
+}
+
+impl Env {
+    /// Runs stages with the output of each going to the input of the next,
+    /// and returns the output of the last one. Programs run at the same time,
+    /// connected by pipes. `name` is the builtin that failures are reported for.
+    /// If [Env::errexit] is set and the last stage is a program that fails, its status is thrown.
+    pub fn pipeline(&mut self, stages: &[Stage], name: &str) -> Result {
+        let _interrupt = BlockInterrupt::new();
+        let mut children = Vec::new();
+        let mut writers = Vec::new();
+        let output = self.run_stages(stages, name, &mut children, &mut writers);
+        // Programs are waited for even if a stage failed, so that they don't linger.
+        let mut last = None;
+        for (program, mut child) in children {
+            if output.is_err() {
+                let _ = child.kill();
+            }
+            last = child.wait().ok().map(|status| (program, status));
+        }
+        for writer in writers {
+            let _ = writer.join();
+        }
+        match output? {
+            Output::Value(value) => Ok(value),
+            Output::Bytes(bytes) => {
+                if let (Some(Stage::Program(_)), Some((program, status))) = (stages.last(), last) {
+                    self.check_status(&program, status, Some(&bytes))?;
+                }
+                Ok(self.gc.rooted(output_value(bytes)))
+            }
+        }
+    }
+
+    /// Runs a program with the shell's input and output, apart from its redirections,
+    /// and returns its exit status. The status of a program killed by a signal is 128 plus
+    /// the signal's number, as in other shells. Failures are reported for the program.
+    pub fn exec(&mut self, program: &Program) -> Result {
+        let Program { args, redirects } = program;
+        let [program, args @ ..] = &args[..] else {
+            return Err(Error::fail("a program can't be empty"));
+        };
+        let name = program.to_string_lossy();
+        let redirections = redirections(redirects, Target::Stdout, &name)?;
+        let mut command = Command::new(program);
+        command.args(args);
+        BlockInterrupt::unblock_in(&mut command);
+        redirections.apply(&mut command, &name)?;
+        let interrupt = BlockInterrupt::new();
+        let status = command.status();
+        drop(interrupt);
+        let status = status.map_err(|err| Error::fail(format!("{name}: {err}")))?;
+        self.check_status(program, status, None)?;
+        let code = match (status.code(), status.signal()) {
+            (Some(code), _) => code,
+            (None, Some(signal)) => 128 + signal,
+            (None, None) => unreachable!("a program either exits or is killed"),
+        };
+        Ok(self.gc.rooted(Value::Number(Number::Int(code.into()))))
+    }
+
+    /// Runs a program like [Env::pipeline] does with one stage, also capturing what it prints
+    /// to stderr, and returns a map of its exit `code` or the `signal` that killed it, and its
+    /// `stdout` and `stderr` unless they're redirected. Since the status is returned,
+    /// it isn't thrown when [Env::errexit] is set.
+    pub fn run(&mut self, program: &Program, name: &str) -> Result {
+        let redirections = redirections(&program.redirects, Target::Pipe, name)?;
+        let [program, args @ ..] = &program.args[..] else {
+            return Err(Error::fail(format!("{name}: a program can't be empty")));
+        };
+        let _interrupt = BlockInterrupt::new();
+        let capture_stderr = redirections.stderr.is_none();
+        let mut command = Command::new(program);
+        command.args(args);
+        BlockInterrupt::unblock_in(&mut command);
+        let reader = redirections.apply(&mut command, name)?;
+        if capture_stderr {
+            command.stderr(Stdio::piped());
+        }
+        let mut child = command
+            .spawn()
+            .map_err(|err| Error::fail(format!("{name}: {}: {err}", program.display())))?;
+        drop(command);
+        // Read at the same time as stdout, so that neither pipe fills up and blocks the program.
+        let stderr = child.stderr.take().map(|mut stderr| {
+            thread::spawn(move || {
+                let mut bytes = Vec::new();
+                stderr.read_to_end(&mut bytes).map(|_| bytes)
+            })
+        });
+        let stdout = reader.map(|reader| read_output(reader, name));
+        let stderr = stderr.map(|stderr| stderr.join().unwrap());
+        let status = child
+            .wait()
+            .map_err(|err| Error::fail(format!("{name}: wait: {err}")))?;
+        let stdout = stdout.transpose()?;
+        let stderr = stderr
+            .transpose()
+            .map_err(|err| Error::fail(format!("{name}: read: {err}")))?;
+        Ok(self.status_map(program, status, stdout.as_deref(), stderr.as_deref()))
+    }
+
+    // Throws the status of a program that failed if errexit is set.
+    fn check_status(
+        &mut self,
+        program: &OsStr,
+        status: ExitStatus,
+        stdout: Option<&[u8]>,
+    ) -> Result<()> {
+        if !self.errexit || status.success() {
+            return Ok(());
+        }
+        Err(Error::Throw(self.status_map(program, status, stdout, None)))
+    }
+
+    // Rooted. Output that wasn't captured is left out.
+    fn status_map(
+        &mut self,
+        program: &OsStr,
+        status: ExitStatus,
+        stdout: Option<&[u8]>,
+        stderr: Option<&[u8]>,
+    ) -> Gc<Value> {
+        let mut entries = vec![("program", output_value(program.as_bytes().to_vec()))];
+        if let Some(code) = status.code() {
+            entries.push(("code", Value::Number(Number::Int(code.into()))));
+        }
+        if let Some(signal) = status.signal() {
+            entries.push(("signal", Value::Number(Number::Int(signal.into()))));
+        }
+        for (key, output) in [("stdout", stdout), ("stderr", stderr)] {
+            if let Some(output) = output {
+                entries.push((key, output_value(output.to_vec())));
+            }
+        }
+        let mut map = OrderedMap::new();
+        for (key, value) in entries {
+            map.insert(Key::String(key.into()), self.gc.rooted(value));
+        }
+        let values: Vec<_> = map.values().copied().collect();
+        let map = self.gc.rooted(Value::Map(map));
+        for value in values {
+            self.gc.unroot(value);
+        }
+        map
+    }
+
+    // Runs the program a command's head names when it isn't a variable.
+    pub(super) fn eval_program(&mut self, name: &str, tail: &[Expr]) -> Result {
+        let mut values = Vec::new();
+        let mut program = None;
+        for expr in tail {
+            match self.eval_expr(expr) {
+                Ok(value) => values.push(value),
+                Err(e) => {
+                    program = Some(Err(e));
+                    break;
+                }
+            }
+        }
+        let program = program.unwrap_or_else(|| {
+            let mut program = Program::from_values(self, &values)
+                .ok_or_else(|| Error::fail(format!("{name}: arguments must be strings")))?;
+            program.args.insert(0, name.into());
+            Ok(program)
+        });
+        for value in values {
+            self.gc.unroot(value);
+        }
+        self.exec(&program?)
+    }
+
+    fn run_stages(
+        &mut self,
+        stages: &[Stage],
+        name: &str,
+        children: &mut Vec<(OsString, Child)>,
+        writers: &mut Vec<JoinHandle<()>>,
+    ) -> Result<Output> {
+        let mut input = Input::Inherit;
+        for (i, stage) in stages.iter().enumerate() {
+            match stage {
+                Stage::Program(program) => {
+                    let redirections = redirections(&program.redirects, Target::Pipe, name)?;
+                    let [program, args @ ..] = &program.args[..] else {
+                        return Err(Error::fail(format!("{name}: a program can't be empty")));
+                    };
+                    let mut command = Command::new(program);
+                    command.args(args);
+                    BlockInterrupt::unblock_in(&mut command);
+                    let mut bytes = None;
+                    match input {
+                        Input::Inherit => (),
+                        Input::Null => {
+                            command.stdin(Stdio::null());
+                        }
+                        Input::Pipe(reader) => {
+                            command.stdin(reader);
+                        }
+                        Input::Bytes(b) => {
+                            command.stdin(Stdio::piped());
+                            bytes = Some(b);
+                        }
+                    }
+                    let reader = redirections.apply(&mut command, name)?;
+                    let mut child = command.spawn().map_err(|err| {
+                        Error::fail(format!("{name}: {}: {err}", program.display()))
+                    })?;
+                    // The command holds the pipe's write end, which must be closed for the reader
+                    // to see the end of the output.
+                    drop(command);
+                    if let Some(bytes) = bytes {
+                        let stdin = child.stdin.take().unwrap();
+                        writers.push(thread::spawn(move || write_input(stdin, bytes)));
+                    }
+                    input = match reader {
+                        Some(reader) => Input::Pipe(reader),
+                        None => Input::Null,
+                    };
+                    children.push((program.clone(), child));
+                }
+                Stage::Function(function) => {
+                    if let Input::Null = input {
+                        // The stage before writes to a file, which the function may read,
+                        // so it's called once the programs before it are done.
+                        for (_, child) in children.iter_mut() {
+                            let _ = child.wait();
+                        }
+                    }
+                    let bytes = match input {
+                        Input::Inherit | Input::Null => Vec::new(),
+                        Input::Pipe(reader) => read_output(reader, name)?,
+                        Input::Bytes(bytes) => bytes,
+                    };
+                    // apply_cmd unroots them.
+                    let arg = self.gc.rooted(output_value(bytes));
+                    self.gc.root(*function);
+                    let value = self.apply_cmd(*function, &[arg])?;
+                    if i == stages.len() - 1 {
+                        return Ok(Output::Value(value));
+                    }
+                    let bytes = match self.gc.get(value) {
+                        Value::String(s) => s.clone().into_bytes(),
+                        Value::Bytes(bytes) => bytes.clone(),
+                        Value::Number(n) => n.to_string().into_bytes(),
+                        _ => {
+                            self.gc.unroot(value);
+                            let message = format!(
+                                "{name}: a function in a pipeline must return a string or bytes"
+                            );
+                            return Err(Error::fail(message));
+                        }
+                    };
+                    self.gc.unroot(value);
+                    input = Input::Bytes(bytes);
+                }
+            }
+        }
+        match input {
+            Input::Inherit | Input::Null => Ok(Output::Bytes(Vec::new())),
+            Input::Pipe(reader) => Ok(Output::Bytes(read_output(reader, name)?)),
+            Input::Bytes(bytes) => Ok(Output::Bytes(bytes)),
+        }
+    }
+}
+
+// The exprs of a command whose head is a builtin, with redirection operators desugared
+// if it runs programs: in `unix` and `run`, and in the `$(list ...)` stages written in `pipe`.
+pub(super) fn builtin_exprs<'a>(builtin: &str, cmd: &'a syntax::Command) -> Cow<'a, [Expr]> {
+    match builtin {
+        "unix" | "run" => cmd.redirected(),
+        "pipe" => {
+            let stage = |expr: &Expr| match expr {
+                Expr::Block(commands, span)
+                    if let [list] = &commands.0[..]
+                        && let Some(Expr::String(head, _)) = list.exprs.first()
+                        && head == "list"
+                        && !list.redirections.is_empty() =>
+                {
+                    let list = syntax::Command {
+                        exprs: list.redirected().into_owned(),
+                        span: list.span,
+                        redirections: Vec::new(),
+                    };
+                    Some(Expr::Block(Rc::new(syntax::Commands(vec![list])), *span))
+                }
+                _ => None,
+            };
+            if !cmd.exprs.iter().any(|expr| stage(expr).is_some()) {
+                return Cow::Borrowed(&cmd.exprs);
+            }
+            let exprs = cmd.exprs.iter();
+            Cow::Owned(
+                exprs
+                    .map(|expr| stage(expr).unwrap_or_else(|| expr.clone()))
+                    .collect(),
+            )
+        }
+        _ => Cow::Borrowed(&cmd.exprs),
+    }
+}
+
+/// Whether `name` is a program in `$PATH`, or a path to one if it has a slash.
+pub fn is_program(name: &str) -> bool {
+    let executable = |path: &Path| {
+        fs::metadata(path).is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+    };
+    if name.contains('/') {
+        return executable(Path::new(name));
+    }
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    !name.is_empty() && std::env::split_paths(&path).any(|dir| executable(&dir.join(name)))
+}
+
+/// Blocks SIGINT in this thread, and in threads it starts, while it's alive,
+/// so that Ctrl-C stops programs that run meanwhile and not the shell.
+/// Only the thread's own signal mask is changed, so that other threads aren't affected.
+struct BlockInterrupt {
+    // The mask to restore.
+    old: libc::sigset_t,
+}
+
+impl BlockInterrupt {
+    fn new() -> Self {
+        unsafe {
+            let mut set = std::mem::zeroed();
+            let mut old = std::mem::zeroed();
+            libc::sigemptyset(&mut set);
+            libc::sigaddset(&mut set, libc::SIGINT);
+            libc::pthread_sigmask(libc::SIG_BLOCK, &set, &mut old);
+            BlockInterrupt { old }
+        }
+    }
+
+    // Programs would inherit the blocked signal otherwise.
+    fn unblock_in(command: &mut Command) {
+        unsafe {
+            command.pre_exec(|| {
+                let mut set = std::mem::zeroed();
+                libc::sigemptyset(&mut set);
+                libc::sigaddset(&mut set, libc::SIGINT);
+                libc::pthread_sigmask(libc::SIG_UNBLOCK, &set, std::ptr::null_mut());
+                Ok(())
+            });
+        }
+    }
+}
+
+impl Drop for BlockInterrupt {
+    fn drop(&mut self) {
+        unsafe {
+            // A Ctrl-C that came while blocked was meant for the programs,
+            // so it's discarded rather than delivered when unblocked.
+            if libc::sigismember(&self.old, libc::SIGINT) == 0 {
+                let mut set = std::mem::zeroed();
+                libc::sigemptyset(&mut set);
+                libc::sigaddset(&mut set, libc::SIGINT);
+                let timeout = libc::timespec {
+                    tv_sec: 0,
+                    tv_nsec: 0,
+                };
+                while libc::sigtimedwait(&set, std::ptr::null_mut(), &timeout) == libc::SIGINT {}
+            }
+            libc::pthread_sigmask(libc::SIG_SETMASK, &self.old, std::ptr::null_mut());
+        }
+    }
+}
+
+// Where a program's output goes.
+enum Target {
+    Pipe,
+    // The shell's own output.
+    Stdout,
+    File(File),
+}
+
+struct Redirections {
+    stdin: Option<File>,
+    stdout: Target,
+    // Inherited by default.
+    stderr: Option<Target>,
+}
+
+// Opens the files of redirections in order.
+fn redirections(redirects: &[Redirect], stdout: Target, name: &str) -> Result<Redirections> {
+    let mut redirections = Redirections {
+        stdin: None,
+        stdout,
+        stderr: None,
+    };
+    for redirect in redirects {
+        let open = |file: &str, options: &OpenOptions| {
+            options
+                .open(file)
+                .map_err(|err| Error::fail(format!("{name}: {file}: {err}")))
+        };
+        let write = |append: bool| {
+            let mut options = OpenOptions::new();
+            if append {
+                options.append(true);
+            } else {
+                options.write(true).truncate(true);
+            }
+            options.create(true);
+            options
+        };
+        match redirect {
+            Redirect::Input(file) => {
+                redirections.stdin = Some(open(file, OpenOptions::new().read(true))?);
+            }
+            Redirect::Output { file, append } => {
+                redirections.stdout = Target::File(open(file, &write(*append))?);
+            }
+            Redirect::Error { file, append } => {
+                redirections.stderr = Some(Target::File(open(file, &write(*append))?));
+            }
+            Redirect::ErrorToOutput => {
+                redirections.stderr = Some(match &redirections.stdout {
+                    Target::Pipe => Target::Pipe,
+                    Target::Stdout => Target::Stdout,
+                    Target::File(file) => Target::File(
+                        file.try_clone()
+                            .map_err(|err| Error::fail(format!("{name}: 2>&1: {err}")))?,
+                    ),
+                });
+            }
+        }
+    }
+    Ok(redirections)
+}
+
+impl Redirections {
+    // Returns the pipe to read the program's output from, unless it all goes to files.
+    fn apply(self, command: &mut Command, name: &str) -> Result<Option<PipeReader>> {
+        if let Some(file) = self.stdin {
+            command.stdin(file);
+        }
+        let stdout = self.stdout;
+        let piped = matches!(stdout, Target::Pipe) || matches!(self.stderr, Some(Target::Pipe));
+        let pipe_error = |err: io::Error| Error::fail(format!("{name}: pipe: {err}"));
+        let (reader, writer) = if piped {
+            let (reader, writer) = io::pipe().map_err(pipe_error)?;
+            (Some(reader), Some(writer))
+        } else {
+            (None, None)
+        };
+        // Both outputs may go to the pipe, each through its own copy of the write end.
+        let pipe = || writer.as_ref().unwrap().try_clone().map_err(pipe_error);
+        match stdout {
+            Target::Pipe => command.stdout(pipe()?),
+            Target::Stdout => command.stdout(Stdio::inherit()),
+            Target::File(file) => command.stdout(file),
+        };
+        match self.stderr {
+            None => (),
+            Some(Target::Pipe) => {
+                command.stderr(pipe()?);
+            }
+            Some(Target::Stdout) => {
+                command.stderr(io::stdout());
+            }
+            Some(Target::File(file)) => {
+                command.stderr(file);
+            }
+        }
+        Ok(reader)
+    }
+}
+
+// What a program printed is a string if it's UTF-8, and bytes otherwise.
+fn output_value(bytes: Vec<u8>) -> Value {
+    match String::from_utf8(bytes) {
+        Ok(string) => Value::String(string),
+        Err(err) => Value::Bytes(err.into_bytes()),
+    }
+}
+
+fn read_output(mut reader: PipeReader, name: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    reader
+        .read_to_end(&mut bytes)
+        .map_err(|err| Error::fail(format!("{name}: read: {err}")))?;
+    Ok(bytes)
+}
+
+// Runs in its own thread, so that a program can write while it's being written to.
+fn write_input(mut stdin: ChildStdin, bytes: Vec<u8>) {
+    // A program may exit without reading all of its input, which is fine.
+    // SIGPIPE is blocked in this thread, so that writing after that doesn't kill the shell.
+    unsafe {
+        let mut set = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGPIPE);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+    }
+    let _ = stdin.write_all(&bytes);
+}
@@ -1,5 +1,8 @@
+pub mod editor;
 pub mod grammar;
+mod helper;
 pub mod interpreter;
+pub mod ordered_map;
 pub mod print;
 pub mod syntax;
 
@@ -1,13 +1,17 @@
-use std::io::{Write, stdin};
-
 use alush::{
+    editor::{Editor, Read},
     grammar,
-    interpreter::{self, Env, print_error},
-    syntax,
+    interpreter::{self, Env, Error},
+    syntax::{self, Commands, Position, Span},
 };
 
 fn shell() {
-    let mut iter = (Box::new(chars()) as Box<dyn Iterator<Item = char>>).peekable();
+    // Everything read so far, to show excerpts in errors.
+    let mut source = String::new();
+    // Lines of a command that isn't complete yet, and where they start in the source.
+    let mut pending = String::new();
+    let mut start = Position::default();
+    let mut editor = Editor::new();
 
     let mut env = Env::new(gc::Strategy::Disabled);
     // {
@@ -17,49 +21,147 @@ fn shell() {
     //     });
     // }
     loop {
-        print!("$ ");
-        std::io::stdout().flush().unwrap();
-        if iter.peek().is_none() {
-            return;
+        let prompt = if pending.is_empty() { "$ " } else { "> " };
+        let line = match editor.read_line(prompt, &env) {
+            Ok(Read::Line(line)) => line,
+            // Ctrl-C throws away the command being typed.
+            Ok(Read::Interrupt) => {
+                for c in pending.chars() {
+                    start.advance(c);
+                }
+                pending.clear();
+                continue;
+            }
+            Ok(Read::Eof) | Err(_) => {
+                // The input ended in the middle of a command.
+                if !pending.is_empty() {
+                    let mut input = syntax::input_from_str(&pending).starting_at(start);
+                    if let Err(e) = grammar::shell(&mut input) {
+                        report_parse_error("<stdin>", &source, &e);
+                    }
+                }
+                return;
+            }
+        };
+        for s in [line.as_str(), "\n"] {
+            pending.push_str(s);
+            source.push_str(s);
         }
-        if let Some(command) = grammar::shell(&mut iter) {
-            let command = syntax::command_from_grammar(&command);
-            // dbg!(&command);
-            match env.eval_cmd(&command) {
-                Err(e) => interpreter::print_error(e),
-                Ok(v) => {
-                    env.print_value(v);
-                    env.gc.unroot(v);
+        let mut input = syntax::input_from_str(&pending).starting_at(start);
+        match grammar::shell(&mut input) {
+            Err(e) if e.is_incomplete() => continue,
+            Err(e) => report_parse_error("<stdin>", &source, &e),
+            Ok(None) => (),
+            Ok(Some(command)) => {
+                let command = syntax::command_from_grammar(&command);
+                // dbg!(&command);
+                // A macro definition expands to nothing.
+                match env.macros.expand(&Commands(vec![command])) {
+                    Err(e) => report(&mut env, "<stdin>", &source, e),
+                    Ok(commands) => {
+                        for command in &commands.0 {
+                            match env.eval_cmd(command) {
+                                Err(e) => report(&mut env, "<stdin>", &source, e),
+                                Ok(v) => {
+                                    env.print_value(v);
+                                    env.gc.unroot(v);
+                                }
+                            }
+                        }
+                    }
                 }
             }
-        } else {
-            println!("error: syntax error");
-            // . ctrl-d to reset the buffer, ctrl-c to exit");
-            drop(iter);
-            iter = (Box::new(chars()) as Box<dyn Iterator<Item = char>>).peekable()
         }
+        // The command is done with, even if it was wrong.
+        for c in pending.chars() {
+            start.advance(c);
+        }
+        pending.clear();
     }
 }
 
-fn chars() -> impl Iterator<Item = char> {
-    stdin().lines().map_while(Result::ok).flat_map(|s| {
-        let mut chars = Vec::new();
-        for c in s.chars() {
-            chars.push(c);
+// Prints an error with an excerpt of the source it happened in.
+fn report(env: &mut Env, name: &str, source: &str, e: Error) {
+    let failure = match e {
+        Error::Throw(value) => {
+            print!("error: uncaught exception: ");
+            env.print_value(value);
+            env.gc.unroot(value);
+            return;
         }
-        chars.push('\n');
-        chars
-    })
+        Error::Fail(failure) => failure,
+    };
+    println!("error: {}", failure.message);
+    if let Some(span) = failure.span {
+        excerpt(name, source, span);
+    }
+    if let Some(builtin) = failure.builtin {
+        println!("  = in builtin `{builtin}`");
+    }
+    for call in failure.trace {
+        let at = call.span.start;
+        match call.name {
+            Some(closure) => println!(
+                "  = in `{closure}`, called at {name}:{}:{}",
+                at.line, at.column
+            ),
+            None => println!(
+                "  = in a closure called at {name}:{}:{}",
+                at.line, at.column
+            ),
+        }
+    }
+}
+
+fn report_parse_error(name: &str, source: &str, e: &grammar::ParseError) {
+    println!("error: syntax error: {e}");
+    excerpt(name, source, e.span());
+}
+
+fn excerpt(name: &str, source: &str, span: Span) {
+    let Span { start, end } = span;
+    println!(" --> {name}:{}:{}", start.line, start.column);
+    let Some(line) = source.lines().nth(start.line - 1) else {
+        return;
+    };
+    let gutter = start.line.to_string();
+    let pad = " ".repeat(gutter.len());
+    // Multiline spans are underlined up to the end of their first line.
+    let end = if end.line == start.line {
+        end.column
+    } else {
+        line.chars().count() + 1
+    };
+    let mut carets = String::new();
+    // Tabs are kept so that carets line up with the excerpt.
+    for c in line.chars().take(start.column - 1) {
+        carets.push(if c == '\t' { '\t' } else { ' ' });
+    }
+    carets.push_str(&"^".repeat(end.saturating_sub(start.column).max(1)));
+    println!("{pad} |");
+    println!("{gutter} | {line}");
+    println!("{pad} | {carets}");
 }
 
-fn dofile(file: String) {
+// Runs a file, returning whether it ran to the end without an uncaught error.
+fn dofile(path: &str, file: String) -> bool {
     let mut env = interpreter::Env::new(gc::Strategy::Default);
     let mut input = syntax::input_from_str(&file);
-    let Some(file) = grammar::file(&mut input) else {
-        println!("syntax error");
-        return;
+    let (commands, errors) = grammar::file_recovering(&mut input);
+    if !errors.is_empty() {
+        for e in &errors {
+            report_parse_error(path, &file, e);
+        }
+        return false;
+    }
+    let commands = syntax::commands_from_grammar(&commands);
+    let commands = match env.macros.expand(&commands) {
+        Ok(commands) => commands,
+        Err(e) => {
+            report(&mut env, path, &file, e);
+            return false;
+        }
     };
-    let commands = syntax::commands_from_grammar(&file);
     let mut result = None;
     for command in commands.0 {
         if let Some(result) = result {
@@ -67,12 +169,32 @@ fn dofile(file: String) {
         }
         match env.eval_cmd(&command) {
             Err(e) => {
-                print_error(e);
-                return;
+                report(&mut env, path, &file, e);
+                return false;
             }
             Ok(v) => result = Some(v),
         }
     }
+    true
+}
+
+// Reports every syntax error in a file, or the first macro error, without running it.
+fn check(path: &str, file: String) -> bool {
+    let mut input = syntax::input_from_str(&file);
+    let (commands, errors) = grammar::file_recovering(&mut input);
+    for e in &errors {
+        report_parse_error(path, &file, e);
+    }
+    if !errors.is_empty() {
+        return false;
+    }
+    let mut env = Env::new(gc::Strategy::Disabled);
+    let commands = syntax::commands_from_grammar(&commands);
+    if let Err(e) = env.macros.expand(&commands) {
+        report(&mut env, path, &file, e);
+        return false;
+    }
+    true
 }
 
 fn main() {
@@ -80,12 +202,25 @@ fn main() {
 
     let args = std::env::args();
     let args: Vec<_> = args.collect();
-    if let Some(path) = args.get(1) {
+    if let [_, flag, path] = &args[..]
+        && flag == "--check"
+    {
+        let Ok(file) = std::fs::read_to_string(path) else {
+            println!("Failed to read path");
+            return;
+        };
+        if !check(path, file) {
+            std::process::exit(1);
+        }
+    } else if let Some(path) = args.get(1) {
         let Ok(file) = std::fs::read_to_string(path) else {
             println!("Failed to read path");
             return;
         };
-        dofile(file);
+        // Like `set -e` in other shells, an uncaught error or errexit makes the script fail.
+        if !dofile(path, file) {
+            std::process::exit(1);
+        }
     } else {
         shell();
     }
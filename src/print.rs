@@ -2,13 +2,15 @@ use crate::syntax::{Command, Expr};
 
 impl Command {
     fn pretty(&self, to: &mut String, depth: usize) {
-        let mut first = true;
-        for e in &self.0 {
-            if !first {
+        for (i, e) in self.exprs.iter().enumerate() {
+            if i > 0 {
                 to.push(' ');
             }
-            first = false;
-            e.pretty(to, depth);
+            // Quoting a redirection operator would make it an argument.
+            match e {
+                Expr::String(op, _) if self.redirections.contains(&i) => to.push_str(op),
+                _ => e.pretty(to, depth),
+            }
         }
     }
 }
@@ -22,7 +24,7 @@ fn tab(to: &mut String, depth: usize) {
 impl Expr {
     pub fn pretty(&self, to: &mut String, mut depth: usize) {
         match self {
-            Expr::String(s) => {
+            Expr::String(s, _) => {
                 to.push('\'');
                 for c in s.chars() {
                     if c == '\'' {
@@ -34,8 +36,8 @@ impl Expr {
                 }
                 to.push('\'');
             }
-            Expr::Closure(commands) | Expr::Block(commands) => {
-                if let Expr::Block(_) = self {
+            Expr::Closure(commands, _) | Expr::Block(commands, _) => {
+                if let Expr::Block(..) = self {
                     to.push('$');
                 }
                 to.push('(');
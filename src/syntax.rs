@@ -1,28 +1,174 @@
-pub type Input = std::iter::Peekable<Box<dyn Iterator<Item = char>>>;
+/// A place in the source. Lines and columns start from 1, columns count chars.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+    /// The offset in bytes from the start of the source.
+    pub offset: usize,
+}
+
+impl Default for Position {
+    fn default() -> Self {
+        Position {
+            line: 1,
+            column: 1,
+            offset: 0,
+        }
+    }
+}
+
+impl Position {
+    /// Moves the position past `c`.
+    pub fn advance(&mut self, c: char) {
+        self.offset += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+    }
+}
+
+impl std::fmt::Display for Position {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// A range of the source, from `start` up to but not including `end`.
+/// The default span is empty and is used for code that doesn't come from source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+}
+
+/// Source chars that keep track of the current position.
+pub struct Input {
+    chars: std::iter::Peekable<Box<dyn Iterator<Item = char>>>,
+    position: Position,
+}
+
+impl Input {
+    pub fn new(chars: Box<dyn Iterator<Item = char>>) -> Self {
+        Input {
+            chars: chars.peekable(),
+            position: Position::default(),
+        }
+    }
+
+    /// Makes positions start from `position`, for source that continues earlier source.
+    pub fn starting_at(mut self, position: Position) -> Self {
+        self.position = position;
+        self
+    }
+
+    pub fn peek(&mut self) -> Option<&char> {
+        self.chars.peek()
+    }
+
+    /// The position of the next char.
+    pub fn here(&self) -> Position {
+        self.position
+    }
+}
+
+impl Iterator for Input {
+    type Item = char;
 
-#[derive(Debug)]
-pub struct Command(pub Vec<Expr>);
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        self.position.advance(c);
+        Some(c)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Command {
+    pub exprs: Vec<Expr>,
+    pub span: Span,
+    /// Where in `exprs` there are unquoted redirection operators, like `>`.
+    /// They're plain strings unless the command runs a program, see [Command::redirected].
+    pub redirections: Vec<usize>,
+}
+
+impl Command {
+    /// The exprs of a command that runs a program, where each redirection operator and
+    /// the file after it desugar to $(redirect op file), with the span of `op file`.
+    pub fn redirected(&self) -> Cow<'_, [Expr]> {
+        if self.redirections.is_empty() {
+            return Cow::Borrowed(&self.exprs);
+        }
+        let mut result = Vec::new();
+        let mut exprs = self.exprs.iter().enumerate();
+        while let Some((i, e)) = exprs.next() {
+            let Expr::String(op, span) = e else {
+                result.push(e.clone());
+                continue;
+            };
+            if !self.redirections.contains(&i) {
+                result.push(e.clone());
+                continue;
+            }
+            let mut span = *span;
+            let mut redirect = vec![
+                Expr::String(String::from("redirect"), span),
+                Expr::String(op.clone(), span),
+            ];
+            // Without a file, `redirect` fails.
+            if op != "2>&1"
+                && let Some((_, file)) = exprs.next()
+            {
+                span.end = file.span().end;
+                redirect.push(file.clone());
+            }
+            let redirect = Command {
+                exprs: redirect,
+                span,
+                redirections: Vec::new(),
+            };
+            result.push(Expr::Block(Rc::new(Commands(vec![redirect])), span));
+        }
+        Cow::Owned(result)
+    }
+}
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Commands(pub Vec<Command>);
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Expr {
-    String(String),
+    String(String, Span),
     // Rc is needed since closures need to own commands without cloning exprs expensively.
-    Closure(Rc<Commands>),
-    Block(Rc<Commands>),
+    Closure(Rc<Commands>, Span),
+    Block(Rc<Commands>, Span),
 }
 
-use std::rc::Rc;
+impl Expr {
+    pub fn span(&self) -> Span {
+        match self {
+            Expr::String(_, span) | Expr::Closure(_, span) | Expr::Block(_, span) => *span,
+        }
+    }
+}
+
+use std::{borrow::Cow, rc::Rc};
 
 use crate::grammar;
 
 pub fn command_from_grammar(g: &grammar::Command) -> Command {
-    let mut c = Command(Vec::new());
-
-    for e in g {
-        c.0.push(expr_from_grammar(e))
+    let mut c = Command {
+        exprs: Vec::new(),
+        span: g.span,
+        redirections: Vec::new(),
+    };
+
+    for (i, e) in g.exprs.iter().enumerate() {
+        if i > 0 && grammar::redirection(e).is_some() {
+            c.redirections.push(i);
+        }
+        c.exprs.push(expr_from_grammar(e));
     }
 
     c
@@ -44,27 +190,38 @@ pub fn expr_from_grammar(g: &grammar::Expr) -> Expr {
         grammar::Expr::Commands {
             dollar: false,
             value,
-        } => Expr::Closure(Rc::new(commands_from_grammar(value))),
+            span,
+        } => Expr::Closure(Rc::new(commands_from_grammar(value)), *span),
         // $(...) is a block.
         grammar::Expr::Commands {
             dollar: true,
             value,
-        } => Expr::Block(Rc::new(commands_from_grammar(value))),
+            span,
+        } => Expr::Block(Rc::new(commands_from_grammar(value)), *span),
         // foo is a string.
         grammar::Expr::String {
             dollar: false,
             value,
-        } => Expr::String(value.into()),
-        // $x desugars to $(get x)
+            span,
+            ..
+        } => Expr::String(value.into(), *span),
+        // $x desugars to $(get x), all of which get the span of $x.
         grammar::Expr::String {
             dollar: true,
             value,
+            span,
+            ..
         } => {
-            let mut command = Command(Vec::new());
-            command.0.push(Expr::String(String::from("get")));
-            command.0.push(Expr::String(value.into()));
+            let span = *span;
+            let mut command = Command {
+                exprs: Vec::new(),
+                span,
+                redirections: Vec::new(),
+            };
+            command.exprs.push(Expr::String(String::from("get"), span));
+            command.exprs.push(Expr::String(value.into(), span));
             let commands = vec![command];
-            Expr::Block(Rc::new(Commands(commands)))
+            Expr::Block(Rc::new(Commands(commands)), span)
         }
     }
 }
@@ -76,9 +233,5 @@ pub fn input_from_str(s: &str) -> Input {
         chars.push(c);
     }
 
-    let heap = Box::new(chars.into_iter());
-
-    let fat = heap as Box<dyn Iterator<Item = _>>;
-
-    fat.peekable()
+    Input::new(Box::new(chars.into_iter()))
 }